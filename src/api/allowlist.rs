@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use anyhow::{Error, anyhow};
+use gmod::{lua, lua_function};
+use lazy_static::lazy_static;
+
+use crate::runtime;
+
+lazy_static! {
+    /// Allowed query text, compared whole so nothing else can match it.
+    static ref ALLOWED_QUERIES: RwLock<HashSet<String>> = RwLock::new(HashSet::new());
+}
+
+/// Surrounding whitespace is ignored so the same query can be written on its
+/// own lines in Lua.
+fn normalize(text: &str) -> &str {
+    text.trim()
+}
+
+fn insert(text: &str) {
+    ALLOWED_QUERIES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(normalize(text).to_string());
+}
+
+fn is_allowed(text: &str) -> bool {
+    ALLOWED_QUERIES
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .contains(normalize(text))
+}
+
+/// The list is meant to be fixed at startup, before any player input can
+/// reach it, so `operation` fails once the server ticks in strict mode.
+pub fn check_startup(l: lua::State, operation: &str) -> anyhow::Result<()> {
+    if runtime::strict_queries() && runtime::is_server_ticking(l) {
+        return Err(anyhow!(
+            "{} only works during startup while NEO4J_STRICT_QUERIES is on",
+            operation
+        ));
+    }
+    Ok(())
+}
+
+/// Allows query text on behalf of `operation`, during startup only.
+pub fn register(l: lua::State, operation: &str, text: &str) -> anyhow::Result<()> {
+    check_startup(l, operation)?;
+    insert(text);
+    Ok(())
+}
+
+/// Rejects query text that was not registered in advance when
+/// `NEO4J_STRICT_QUERIES` is on.
+pub fn check_allowed(text: &str) -> anyhow::Result<()> {
+    if !runtime::strict_queries() || is_allowed(text) {
+        return Ok(());
+    }
+
+    let mut preview: String = normalize(text).chars().take(60).collect();
+    if preview.len() < normalize(text).len() {
+        preview.push_str("...");
+    }
+    Err(anyhow!(
+        "Query {:?} is not allowed while NEO4J_STRICT_QUERIES is on; register its text with \
+         neo4j.AllowQuery at startup or load it with neo4j.LoadQueries",
+        preview
+    ))
+}
+
+#[lua_function]
+pub fn allow_query(l: lua::State) -> anyhow::Result<i32> {
+    let text = l.check_string(1)?;
+    if normalize(&text).is_empty() {
+        return Err(Error::msg("AllowQuery expects query text"));
+    }
+
+    register(l, "AllowQuery", &text)?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_exact_text_is_allowed() {
+        insert("  MATCH (n:AllowlistTest) RETURN n\n");
+
+        assert!(is_allowed("MATCH (n:AllowlistTest) RETURN n"));
+        assert!(is_allowed("\tMATCH (n:AllowlistTest) RETURN n  "));
+        assert!(!is_allowed("MATCH (n:AllowlistTest) RETURN n LIMIT 1"));
+        assert!(!is_allowed("MATCH (n:AllowlistTest)  RETURN n"));
+        assert!(!is_allowed("match (n:AllowlistTest) return n"));
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::anyhow;
+use gmod::{LuaReference, lua, wait_lua_tick};
+use neo4rs::{Graph, Query};
+use tokio::sync::Mutex;
+
+use crate::api::graph::{LuaNeoGraph, handle_graph_execution};
+use crate::api::query::LuaNeoQuery;
+use crate::api::result::{ResultSet, push_rows, push_summary};
+use crate::api::transaction::handle_execution;
+
+/// A query of a batch, prepared on the game thread.
+pub struct BatchQuery {
+    pub query: Query,
+    pub columns: Option<Vec<String>>,
+}
+
+pub struct BatchOutcome {
+    /// One entry per query that was run, in order
+    pub results: Vec<anyhow::Result<ResultSet>>,
+    pub committed: bool,
+    pub error: Option<String>,
+}
+
+pub fn read_queries(
+    l: lua::State,
+    graph: &LuaNeoGraph,
+    index: i32,
+) -> anyhow::Result<Vec<BatchQuery>> {
+    if !l.is_table(index) {
+        return Err(anyhow!("Expected a table of Neo4jQuery objects"));
+    }
+
+    let mut queries = Vec::new();
+    for i in 1..=l.len(index) {
+        l.raw_geti(index, i);
+        let query = l
+            .get_struct::<LuaNeoQuery>(l.get_top())
+            .map_err(|_| anyhow!("Element {} is not a Neo4jQuery", i))
+            .and_then(|neo_query| {
+                Ok(BatchQuery {
+                    query: graph
+                        .prepare(neo_query)
+                        .map_err(|err| anyhow!("Query {}: {}", i, err))?,
+                    columns: neo_query.columns(),
+                })
+            });
+        l.pop();
+        queries.push(query?);
+    }
+
+    Ok(queries)
+}
+
+/// Runs every query in one transaction, rolling back on the first failure.
+pub async fn run_atomic(graph: Graph, queries: Vec<BatchQuery>) -> BatchOutcome {
+    let mut outcome = BatchOutcome {
+        results: Vec::new(),
+        committed: false,
+        error: None,
+    };
+
+    let txn = match graph.start_txn().await {
+        Ok(txn) => Mutex::new(Some(txn)),
+        Err(err) => {
+            outcome.error = Some(format!("Could not start transaction: {}", err));
+            return outcome;
+        }
+    };
+
+    for (i, batch_query) in queries.into_iter().enumerate() {
+        // Rolls the transaction back itself if the query fails
+        match handle_execution(txn.lock().await, batch_query.query).await {
+            Ok(rows) => outcome
+                .results
+                .push(Ok(ResultSet::new(rows, batch_query.columns))),
+            Err(err) => {
+                outcome.error = Some(format!("Query {} failed: {}", i + 1, err));
+                outcome.results.push(Err(err));
+                return outcome;
+            }
+        }
+    }
+
+    let commit = match txn.into_inner() {
+        Some(txn) => txn.commit().await.map_err(|err| err.to_string()),
+        None => Err("the transaction was already closed".to_string()),
+    };
+    match commit {
+        Ok(()) => outcome.committed = true,
+        Err(err) => outcome.error = Some(format!("Commit failed: {}", err)),
+    }
+
+    outcome
+}
+
+/// Runs every query as its own auto-commit, one after another in order, so
+/// later queries can build on earlier ones. A failure doesn't stop the rest.
+pub async fn run_independent(graph: Graph, queries: Vec<BatchQuery>) -> BatchOutcome {
+    let mut outcome = BatchOutcome {
+        results: Vec::new(),
+        committed: false,
+        error: None,
+    };
+
+    for (i, batch_query) in queries.into_iter().enumerate() {
+        let result = handle_graph_execution(graph.clone(), batch_query.query)
+            .await
+            .map(|rows| ResultSet::new(rows, batch_query.columns));
+
+        if let Err(err) = &result
+            && outcome.error.is_none()
+        {
+            outcome.error = Some(format!("Query {} failed: {}", i + 1, err));
+        }
+        outcome.results.push(result);
+    }
+    outcome.committed = outcome.error.is_none();
+
+    outcome
+}
+
+fn push_outcome(l: lua::State, outcome: &BatchOutcome) -> anyhow::Result<()> {
+    l.new_table();
+    for (i, result) in outcome.results.iter().enumerate() {
+        l.new_table();
+        match result {
+            Ok(set) => {
+                l.push_string("rows");
+                push_rows(l, &set.rows)?;
+                l.raw_set_table(-3);
+
+                l.push_string("summary");
+                push_summary(l, set);
+                l.raw_set_table(-3);
+            }
+            Err(err) => {
+                l.push_string("error");
+                l.push_string(&err.to_string());
+                l.raw_set_table(-3);
+            }
+        }
+        l.raw_seti(-2, i as i32 + 1);
+    }
+
+    Ok(())
+}
+
+pub fn dispatch_batch_callback(callback: LuaReference, outcome: BatchOutcome) {
+    wait_lua_tick(move |l| {
+        let _ = l.pcall_func_ref(callback, || {
+            match &outcome.error {
+                Some(err) => l.push_string(err),
+                None => l.push_nil(),
+            }
+            let _ = push_outcome(l, &outcome)
+                .map_err(|e| panic!("Could not convert bolt map to Lua table: {}", e));
+            l.push_boolean(outcome.committed);
+
+            1
+        });
+    });
+}
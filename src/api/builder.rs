@@ -0,0 +1,308 @@
+use std::sync::{RwLock, RwLockWriteGuard};
+
+use anyhow::{Error, anyhow};
+use gmod::rstruct::RStruct;
+use gmod::{LUA_TNIL, LUA_TSTRING, LUA_TUSERDATA, lua, lua_function, register_lua_rstruct};
+use neo4rs::{BoltMap, BoltNull, BoltString, BoltType};
+
+use crate::api::allowlist::check_allowed;
+use crate::api::ident::LuaNeoIdent;
+use crate::api::query::{LuaNeoQuery, NeoQuery, ParamValidation};
+use crate::mapping::{lua_type_name, lua_value_to_bolttype};
+use crate::runtime;
+
+/// Query text built clause by clause. Values never end up in the text, they
+/// are stored as `$p0`, `$p1`, ... parameters.
+#[derive(Default)]
+pub struct CypherBuilder {
+    clauses: Vec<(&'static str, String)>,
+    params: BoltMap,
+}
+
+impl CypherBuilder {
+    fn add_param(&mut self, value: BoltType) -> String {
+        let name = format!("p{}", self.params.len());
+        self.params.put(BoltString::from(name.as_str()), value);
+        format!("${}", name)
+    }
+
+    fn add_clause(&mut self, keyword: &'static str, body: String) {
+        // Consecutive Where calls narrow the same clause
+        if keyword == "WHERE"
+            && let Some((last, previous)) = self.clauses.last_mut()
+            && *last == "WHERE"
+        {
+            *previous = format!("({}) AND ({})", previous, body);
+            return;
+        }
+
+        self.clauses.push((keyword, body));
+    }
+
+    pub fn text(&self) -> String {
+        self.clauses
+            .iter()
+            .map(|(keyword, body)| {
+                if body.is_empty() {
+                    keyword.to_string()
+                } else {
+                    format!("{} {}", keyword, body)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub struct LuaNeoCypher(pub RwLock<CypherBuilder>);
+
+impl LuaNeoCypher {
+    pub fn write(&self) -> RwLockWriteGuard<'_, CypherBuilder> {
+        self.0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+register_lua_rstruct!(LuaNeoCypher, c"Neo4jCypher", &[
+    (c"Match", match_clause),
+    (c"OptionalMatch", optional_match),
+    (c"Where", where_clause),
+    (c"With", with),
+    (c"Unwind", unwind),
+    (c"Create", create),
+    (c"Merge", merge),
+    (c"Set", set),
+    (c"Delete", delete),
+    (c"DetachDelete", detach_delete),
+    (c"Return", return_clause),
+    (c"OrderBy", order_by),
+    (c"Skip", skip),
+    (c"Limit", limit),
+    (c"Build", build),
+]);
+
+#[lua_function]
+pub fn new_cypher(l: lua::State) -> anyhow::Result<i32> {
+    l.push_struct::<LuaNeoCypher>(LuaNeoCypher(RwLock::new(CypherBuilder::default())));
+    Ok(1)
+}
+
+/// Reads alternating text and values from argument 2 onwards. Text is used
+/// as is, values become parameters and `Neo4jIdent`s are inserted escaped.
+fn read_fragments(l: lua::State, builder: &mut CypherBuilder) -> anyhow::Result<String> {
+    let mut body = String::new();
+    for i in 2..=l.get_top() {
+        let is_text = i % 2 == 0;
+        if is_text {
+            if l.lua_type(i) != LUA_TSTRING {
+                return Err(anyhow!(
+                    "Argument {} must be query text, got {}",
+                    i - 1,
+                    lua_type_name(l.lua_type(i))
+                ));
+            }
+            body.push_str(&l.get_string_unchecked(i));
+            continue;
+        }
+
+        match l.lua_type(i) {
+            LUA_TUSERDATA => {
+                let ident = l
+                    .get_struct::<LuaNeoIdent>(i)
+                    .map_err(|_| anyhow!("Argument {} is not a Neo4jIdent", i - 1))?;
+                body.push_str(&ident.0);
+            }
+            LUA_TNIL => body.push_str(&builder.add_param(BoltType::Null(BoltNull))),
+            _ => {
+                let value = lua_value_to_bolttype(l, i, &format!("argument {}", i - 1))?;
+                body.push_str(&builder.add_param(value));
+            }
+        }
+    }
+
+    Ok(body.trim().to_string())
+}
+
+fn add_clause(l: lua::State, keyword: &'static str, required: bool) -> anyhow::Result<i32> {
+    let cypher = l.get_struct::<LuaNeoCypher>(1)?;
+    let mut builder = cypher.write();
+
+    let body = read_fragments(l, &mut builder)?;
+    if required && body.is_empty() {
+        return Err(anyhow!("{} needs query text", keyword));
+    }
+    builder.add_clause(keyword, body);
+
+    // Return the builder itself so calls can be chained
+    l.push_value(1);
+    Ok(1)
+}
+
+fn add_count(l: lua::State, keyword: &'static str) -> anyhow::Result<i32> {
+    let cypher = l.get_struct::<LuaNeoCypher>(1)?;
+
+    if !l.is_number(2) || l.to_number(2) < 0.0 || l.to_number(2).fract() != 0.0 {
+        return Err(anyhow!("{} expects a whole number of rows", keyword));
+    }
+    let count = lua_value_to_bolttype(l, 2, keyword)?;
+
+    let mut builder = cypher.write();
+    let param = builder.add_param(count);
+    builder.add_clause(keyword, param);
+
+    l.push_value(1);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn match_clause(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "MATCH", true)
+}
+
+#[lua_function]
+pub fn optional_match(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "OPTIONAL MATCH", true)
+}
+
+#[lua_function]
+pub fn where_clause(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "WHERE", true)
+}
+
+#[lua_function]
+pub fn with(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "WITH", true)
+}
+
+#[lua_function]
+pub fn unwind(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "UNWIND", true)
+}
+
+#[lua_function]
+pub fn create(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "CREATE", true)
+}
+
+#[lua_function]
+pub fn merge(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "MERGE", true)
+}
+
+#[lua_function]
+pub fn set(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "SET", true)
+}
+
+#[lua_function]
+pub fn delete(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "DELETE", true)
+}
+
+#[lua_function]
+pub fn detach_delete(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "DETACH DELETE", true)
+}
+
+#[lua_function]
+pub fn return_clause(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "RETURN", true)
+}
+
+#[lua_function]
+pub fn order_by(l: lua::State) -> anyhow::Result<i32> {
+    add_clause(l, "ORDER BY", true)
+}
+
+#[lua_function]
+pub fn skip(l: lua::State) -> anyhow::Result<i32> {
+    add_count(l, "SKIP")
+}
+
+#[lua_function]
+pub fn limit(l: lua::State) -> anyhow::Result<i32> {
+    add_count(l, "LIMIT")
+}
+
+#[lua_function]
+pub fn build(l: lua::State) -> anyhow::Result<i32> {
+    let cypher = l.get_struct::<LuaNeoCypher>(1)?;
+    let builder = cypher.write();
+
+    if builder.clauses.is_empty() {
+        return Err(Error::msg("Cannot build an empty query"));
+    }
+
+    let text = builder.text();
+    check_allowed(&text)?;
+
+    let mut query = NeoQuery::new(text);
+    query.params = builder.params.clone();
+
+    // Only text written by hand can reference parameters that don't exist
+    if runtime::param_validation() == ParamValidation::Error {
+        query.validate(ParamValidation::Error)?;
+    }
+
+    l.push_struct::<LuaNeoQuery>(LuaNeoQuery::new(query));
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use neo4rs::BoltInteger;
+
+    use super::*;
+
+    fn integer(value: i64) -> BoltType {
+        BoltType::Integer(BoltInteger::new(value))
+    }
+
+    #[test]
+    fn params_are_numbered_in_order() {
+        let mut builder = CypherBuilder::default();
+        assert_eq!(builder.add_param(integer(1)), "$p0");
+        assert_eq!(builder.add_param(integer(2)), "$p1");
+        assert_eq!(builder.params.get::<i64>("p0").unwrap(), 1);
+        assert_eq!(builder.params.get::<i64>("p1").unwrap(), 2);
+    }
+
+    #[test]
+    fn consecutive_where_clauses_are_merged() {
+        let mut builder = CypherBuilder::default();
+        builder.add_clause("MATCH", "(n:Player)".to_string());
+        builder.add_clause("WHERE", "n.level > $p0".to_string());
+        builder.add_clause("WHERE", "n.banned = false OR n.vip".to_string());
+        builder.add_clause("WHERE", "n.name <> $p1".to_string());
+        builder.add_clause("RETURN", "n".to_string());
+
+        assert_eq!(
+            builder.text(),
+            "MATCH (n:Player)\nWHERE ((n.level > $p0) AND (n.banned = false OR n.vip)) AND (n.name \
+             <> $p1)\nRETURN n"
+        );
+    }
+
+    #[test]
+    fn separated_where_clauses_stay_apart() {
+        let mut builder = CypherBuilder::default();
+        builder.add_clause("MATCH", "(n)".to_string());
+        builder.add_clause("WHERE", "n.x = 1".to_string());
+        builder.add_clause("WITH", "n".to_string());
+        builder.add_clause("WHERE", "n.y = 2".to_string());
+        builder.add_clause("DETACH DELETE", "n".to_string());
+
+        assert_eq!(
+            builder.text(),
+            "MATCH (n)\nWHERE n.x = 1\nWITH n\nWHERE n.y = 2\nDETACH DELETE n"
+        );
+    }
+
+    #[test]
+    fn empty_bodies_leave_only_the_keyword() {
+        let mut builder = CypherBuilder::default();
+        builder.add_clause("RETURN", String::new());
+        assert_eq!(builder.text(), "RETURN");
+    }
+}
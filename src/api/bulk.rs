@@ -0,0 +1,215 @@
+use anyhow::{Error, anyhow};
+use futures::StreamExt;
+use gmod::{LUA_TFUNCTION, LuaReference, lua, lua_function, wait_lua_tick};
+use neo4rs::{BoltList, BoltString, BoltType, Graph};
+
+use crate::api::allowlist::check_allowed;
+use crate::api::graph::LuaNeoGraph;
+use crate::api::query::NeoQuery;
+use crate::cypher::parameter_names;
+use crate::mapping::{lua_table_to_boltlist, lua_type_name};
+use crate::runtime;
+
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+const DEFAULT_PARALLEL: usize = 1;
+
+struct ChunkResult {
+    index: usize,
+    rows: usize,
+    result: anyhow::Result<()>,
+}
+
+async fn write_chunk(
+    graph: Graph,
+    text: String,
+    index: usize,
+    chunk: Vec<BoltType>,
+) -> ChunkResult {
+    let rows = chunk.len();
+
+    let mut query = NeoQuery::new(text);
+    query.params.put(
+        BoltString::from("rows"),
+        BoltType::List(BoltList { value: chunk }),
+    );
+    let result = graph.run(query.to_query()).await.map_err(Error::from);
+
+    ChunkResult {
+        index,
+        rows,
+        result,
+    }
+}
+
+fn dispatch_progress(progress: LuaReference, written: usize, total: usize, err: Option<String>) {
+    wait_lua_tick(move |l| {
+        // The progress callback is called once per chunk, so keep its reference
+        l.from_reference(progress);
+        let success = l.pcall_ignore(|| {
+            l.push_number(written as f64);
+            l.push_number(total as f64);
+            match &err {
+                Some(err) => l.push_string(err),
+                None => l.push_nil(),
+            }
+            0
+        });
+        if !success {
+            eprintln!("[neo4j] BulkWrite progress callback raised an error");
+        }
+    });
+}
+
+fn dispatch_done(
+    callback: LuaReference,
+    progress: Option<LuaReference>,
+    written: usize,
+    chunks: usize,
+    errors: Vec<(usize, String)>,
+) {
+    wait_lua_tick(move |l| {
+        if let Some(progress) = progress {
+            l.dereference(progress);
+        }
+
+        let _ = l.pcall_func_ref(callback, || {
+            if errors.is_empty() {
+                l.push_nil();
+            } else {
+                l.push_string(&format!("{} of {} chunks failed", errors.len(), chunks));
+            }
+
+            l.new_table();
+            l.push_string("written");
+            l.push_number(written as f64);
+            l.raw_set_table(-3);
+
+            l.push_string("chunks");
+            l.push_number(chunks as f64);
+            l.raw_set_table(-3);
+
+            // Keyed by chunk number, starting at 1
+            l.push_string("errors");
+            l.new_table();
+            for (index, err) in &errors {
+                l.push_string(err);
+                l.raw_seti(-2, *index as i32 + 1);
+            }
+            l.raw_set_table(-3);
+
+            1
+        });
+    });
+}
+
+#[lua_function]
+pub fn bulk_write(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    graph_container.check_writable("BulkWrite")?;
+    let text = l.check_string(2)?;
+    check_allowed(&text)?;
+
+    if !parameter_names(&text).contains("rows") {
+        return Err(Error::msg(
+            "BulkWrite queries must read their rows from $rows, e.g. UNWIND $rows AS row ...",
+        ));
+    }
+
+    if !l.is_table(3) {
+        return Err(Error::msg(
+            "BulkWrite expects an array of rows as its second argument",
+        ));
+    }
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(4) && !l.is_table(4) {
+        return Err(Error::msg("Options argument must be a table of options"));
+    }
+
+    let mut chunk_size = DEFAULT_CHUNK_SIZE;
+    let mut parallel = DEFAULT_PARALLEL;
+    if l.is_table(4) {
+        l.get_field(4, c"chunk_size");
+        if l.is_number(-1) {
+            chunk_size = l.to_number(-1).max(1.0) as usize;
+        }
+        l.pop_n(1);
+
+        l.get_field(4, c"parallel");
+        if l.is_number(-1) {
+            parallel = l.to_number(-1).max(1.0) as usize;
+        }
+        l.pop_n(1);
+    }
+
+    let mut check = NeoQuery::new(text.clone());
+    check
+        .params
+        .put(BoltString::from("rows"), BoltType::List(BoltList::new()));
+    check.validate(runtime::param_validation())?;
+
+    // Lua values can only be read on the game thread, everything else
+    // happens on the worker runtime
+    let rows = lua_table_to_boltlist(l, 3, "rows")
+        .map_err(|err| anyhow!("Invalid BulkWrite row: {}", err))?;
+    let graph = graph_container.graph()?;
+
+    // Everything is checked before taking references to the callbacks, so
+    // an error can't leak them
+    if !l.is_none_or_nil(5) && l.lua_type(5) != LUA_TFUNCTION {
+        return Err(anyhow!(
+            "BulkWrite expects a progress function or nil as its fourth argument, got {}",
+            lua_type_name(l.lua_type(5))
+        ));
+    }
+    if l.lua_type(6) != LUA_TFUNCTION {
+        return Err(anyhow!(
+            "BulkWrite expects a completion function as its fifth argument, got {}",
+            lua_type_name(l.lua_type(6))
+        ));
+    }
+    let progress = if l.is_none_or_nil(5) {
+        None
+    } else {
+        Some(l.check_function(5)?)
+    };
+    let callback = l.check_function(6)?;
+
+    runtime::run_async(graph_container.track(async move {
+        let total = rows.len();
+        let mut chunks: Vec<Vec<BoltType>> = Vec::new();
+        let mut rows = rows.value.into_iter().peekable();
+        while rows.peek().is_some() {
+            chunks.push(rows.by_ref().take(chunk_size).collect());
+        }
+        let chunk_count = chunks.len();
+
+        let mut writes = futures::stream::iter(chunks.into_iter().enumerate())
+            .map(|(index, chunk)| write_chunk(graph.clone(), text.clone(), index, chunk))
+            .buffer_unordered(parallel);
+
+        let mut written = 0;
+        let mut errors = Vec::new();
+        while let Some(chunk) = writes.next().await {
+            let err = match chunk.result {
+                Ok(()) => {
+                    written += chunk.rows;
+                    None
+                }
+                Err(err) => {
+                    let err = format!("Chunk {} failed: {}", chunk.index + 1, err);
+                    errors.push((chunk.index, err.clone()));
+                    Some(err)
+                }
+            };
+
+            if let Some(progress) = progress {
+                dispatch_progress(progress, written, total, err);
+            }
+        }
+
+        dispatch_done(callback, progress, written, chunk_count, errors);
+    }));
+
+    Ok(0)
+}
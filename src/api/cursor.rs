@@ -0,0 +1,205 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Error;
+use futures::{Stream, TryStreamExt};
+use gmod::rstruct::RStruct;
+use gmod::{lua, lua_function, register_lua_rstruct};
+use neo4rs::{BoltMap, Graph, Query};
+use tokio::sync::Mutex;
+
+use crate::api::graph::GraphState;
+use crate::api::promise::LuaNeoPromise;
+use crate::api::result::{ResultSet, dispatch_callback};
+use crate::runtime;
+
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+type RowStream = Pin<Box<dyn Stream<Item = Result<BoltMap, neo4rs::Error>> + Send>>;
+
+enum CursorStream {
+    /// The query has not been sent yet
+    Pending(Query),
+    Open(RowStream),
+    Closed,
+}
+
+struct CursorState {
+    stream: CursorStream,
+    /// The row read ahead to find out whether there are more
+    peeked: Option<BoltMap>,
+}
+
+pub struct LuaNeoCursor {
+    /// Looked up on every call, so the cursor stops once the graph is closed
+    graph: Arc<GraphState>,
+    state: Arc<Mutex<CursorState>>,
+    exhausted: Arc<AtomicBool>,
+    columns: Option<Vec<String>>,
+    page_size: usize,
+}
+
+impl LuaNeoCursor {
+    pub fn new(
+        graph: Arc<GraphState>,
+        query: Query,
+        columns: Option<Vec<String>>,
+        page_size: usize,
+    ) -> Self {
+        let state = CursorState {
+            stream: CursorStream::Pending(query),
+            peeked: None,
+        };
+
+        Self {
+            graph,
+            state: Arc::new(Mutex::new(state)),
+            exhausted: Arc::new(AtomicBool::new(false)),
+            columns,
+            page_size,
+        }
+    }
+}
+
+register_lua_rstruct!(LuaNeoCursor, c"Neo4jCursor", &[
+    (c"Next", next),
+    (c"HasMore", has_more),
+    (c"Close", close),
+]);
+
+async fn next_row(stream: &mut RowStream) -> anyhow::Result<Option<BoltMap>> {
+    Ok(stream.try_next().await?)
+}
+
+async fn read_page(
+    state: &mut CursorState,
+    graph: Graph,
+    count: usize,
+) -> anyhow::Result<Vec<BoltMap>> {
+    if let CursorStream::Pending(query) = &state.stream {
+        let stream = graph.execute(query.clone()).await?;
+        state.stream =
+            CursorStream::Open(Box::pin(stream.into_stream_as::<BoltMap>().into_stream()));
+    }
+
+    let CursorStream::Open(stream) = &mut state.stream else {
+        return Ok(Vec::new());
+    };
+
+    let mut output: Vec<BoltMap> = state.peeked.take().into_iter().collect();
+    while output.len() < count {
+        match next_row(stream).await? {
+            Some(row) => output.push(row),
+            None => break,
+        }
+    }
+
+    // Read one row ahead, so HasMore is accurate without an empty last page
+    if output.len() == count {
+        state.peeked = next_row(stream).await?;
+    }
+
+    if state.peeked.is_none() {
+        // Dropping the stream hands its connection back to the pool
+        state.stream = CursorStream::Closed;
+    }
+
+    Ok(output)
+}
+
+async fn handle_next(
+    state: Arc<Mutex<CursorState>>,
+    graph: Graph,
+    exhausted: Arc<AtomicBool>,
+    count: usize,
+) -> anyhow::Result<Vec<BoltMap>> {
+    let mut guard = state.lock().await;
+    let result = read_page(&mut guard, graph, count).await;
+
+    if result.is_err() {
+        guard.stream = CursorStream::Closed;
+        guard.peeked = None;
+    }
+    if matches!(guard.stream, CursorStream::Closed) {
+        exhausted.store(true, Ordering::SeqCst);
+    }
+
+    result
+}
+
+#[lua_function]
+pub fn next(l: lua::State) -> anyhow::Result<i32> {
+    let cursor = l.get_struct::<LuaNeoCursor>(1)?;
+
+    let count = if l.is_none_or_nil(2) {
+        cursor.page_size
+    } else if l.is_number(2) && l.to_number(2) >= 1.0 {
+        l.to_number(2) as usize
+    } else {
+        return Err(Error::msg(
+            "Next expects a positive number of rows as its first argument",
+        ));
+    };
+
+    let graph = match cursor.graph.graph() {
+        Ok(graph) => graph,
+        Err(err) => {
+            release(cursor);
+            return Err(err);
+        }
+    };
+    let state = cursor.state.clone();
+    let exhausted = cursor.exhausted.clone();
+
+    // Without a callback, hand back a promise instead
+    if l.is_none_or_nil(3) {
+        let page = handle_next(state, graph, exhausted, count);
+        let promise = LuaNeoPromise::spawn(cursor.graph.track(page));
+        l.push_struct::<LuaNeoPromise>(promise);
+        return Ok(1);
+    }
+    let callback = l.check_function(3)?;
+    let columns = cursor.columns.clone();
+
+    runtime::run_async(cursor.graph.track(async move {
+        let results = handle_next(state, graph, exhausted, count)
+            .await
+            .map(|rows| ResultSet::new(rows, columns));
+
+        dispatch_callback(callback, results);
+    }));
+
+    Ok(0)
+}
+
+#[lua_function]
+pub fn has_more(l: lua::State) -> anyhow::Result<i32> {
+    let cursor = l.get_struct::<LuaNeoCursor>(1)?;
+
+    l.push_boolean(!cursor.exhausted.load(Ordering::SeqCst));
+
+    Ok(1)
+}
+
+/// Drops the stream, handing its connection back to the pool.
+fn release(cursor: &LuaNeoCursor) {
+    cursor.exhausted.store(true, Ordering::SeqCst);
+
+    // A pending Next holds the lock, so release the stream once it is done
+    let state = cursor.state.clone();
+    runtime::run_async(async move {
+        let mut guard = state.lock().await;
+        guard.stream = CursorStream::Closed;
+        guard.peeked = None;
+    });
+}
+
+#[lua_function]
+pub fn close(l: lua::State) -> anyhow::Result<i32> {
+    let cursor = l.get_struct::<LuaNeoCursor>(1)?;
+
+    release(&cursor);
+
+    Ok(0)
+}
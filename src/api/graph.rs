@@ -1,93 +1,328 @@
-use std::sync::Arc;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, RwLock};
 
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use gmod::rstruct::RStruct;
-use gmod::{lua, lua_function, register_lua_rstruct};
+use gmod::{LUA_TBOOLEAN, lua, lua_function, register_lua_rstruct, wait_lua_tick};
+use lazy_static::lazy_static;
 use neo4rs::{BoltMap, Config, Graph, Query};
-use tokio::sync::Mutex;
-
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tokio_util::task::task_tracker::TrackedFuture;
+
+use crate::api::batch::{dispatch_batch_callback, read_queries, run_atomic, run_independent};
+use crate::api::bulk::bulk_write;
+use crate::api::cursor::{DEFAULT_PAGE_SIZE, LuaNeoCursor};
+use crate::api::health::{
+    HealthStatus, health, is_healthy, ping, read_health_check, start_health_check,
+};
+use crate::api::pager::LuaNeoPager;
+use crate::api::promise::LuaNeoPromise;
 use crate::api::query::LuaNeoQuery;
-use crate::api::result::dispatch_callback;
+use crate::api::registry;
+use crate::api::result::{
+    ResultSet, ResultShape, dispatch_callback, dispatch_shaped_callback, push_rows,
+};
+use crate::api::schema::{ensure_constraint, ensure_index, schema};
 use crate::api::transaction::LuaNeoTxn;
+use crate::cypher::find_write;
 use crate::{THREAD_WORKER, runtime};
 
+lazy_static! {
+    /// Keys the password hash in `ConnectionInfo`, fresh for every process.
+    static ref PASSWORD_HASHER: RandomState = RandomState::new();
+}
+
+/// The connection pool shared by every handle to the same graph, and the
+/// work running on it.
+pub struct GraphState {
+    /// Taken by `Graph:Close()`
+    graph: RwLock<Option<Graph>>,
+    tracker: TaskTracker,
+    /// Cancelled by `Graph:Close()`, stops the health check
+    pub closed: CancellationToken,
+    pub health: std::sync::Mutex<HealthStatus>,
+}
+
+impl GraphState {
+    /// The connection pool, unless the graph has been closed.
+    pub fn graph(&self) -> anyhow::Result<Graph> {
+        self.graph
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+            .ok_or_else(|| Error::msg("Graph closed"))
+    }
+
+    /// Tracks work on this graph so `Close` can wait for it.
+    pub fn track<F: Future>(&self, fut: F) -> TrackedFuture<F> {
+        self.tracker.track_future(fut)
+    }
+}
+
 pub struct LuaNeoGraph {
-    pub graph: Graph,
+    pub state: Arc<GraphState>,
+    /// Set on handles from `Graph:ReadOnly()`, which refuse anything that
+    /// could write
+    pub read_only: bool,
 }
 
 impl LuaNeoGraph {
     pub fn new(config: Config) -> anyhow::Result<Self> {
         THREAD_WORKER.block_on(async {
             let graph = Graph::connect(config).await?;
-            Ok(Self { graph })
+            Ok(Self {
+                state: Arc::new(GraphState {
+                    graph: RwLock::new(Some(graph)),
+                    tracker: TaskTracker::new(),
+                    closed: CancellationToken::new(),
+                    health: std::sync::Mutex::new(HealthStatus::default()),
+                }),
+                read_only: false,
+            })
         })
     }
+
+    /// Another handle to the same pool.
+    pub fn share(&self, read_only: bool) -> Self {
+        Self {
+            state: self.state.clone(),
+            read_only,
+        }
+    }
+
+    /// The connection pool, unless the graph has been closed.
+    pub fn graph(&self) -> anyhow::Result<Graph> {
+        self.state.graph()
+    }
+
+    /// Tracks work on this graph so `Close` can wait for it.
+    pub fn track<F: Future>(&self, fut: F) -> TrackedFuture<F> {
+        self.state.track(fut)
+    }
+
+    /// Fails for read-only handles, naming the refused operation.
+    pub fn check_writable(&self, operation: &str) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(anyhow!("{} is not allowed on a read-only graph", operation));
+        }
+        Ok(())
+    }
+
+    /// Fails for read-only handles if the query text could write.
+    pub fn check_query(&self, text: &str) -> anyhow::Result<()> {
+        if self.read_only
+            && let Some(write) = find_write(text)
+        {
+            return Err(anyhow!(
+                "Query contains {}, which is not allowed on a read-only graph",
+                write
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks a query may run on this handle and builds it for execution.
+    pub fn prepare(&self, neo_query: &LuaNeoQuery) -> anyhow::Result<Query> {
+        self.check_query(&neo_query.read().text)?;
+        neo_query.prepare()
+    }
 }
 
 register_lua_rstruct!(LuaNeoGraph, c"Neo4jGraph", &[
     (c"Execute", execute),
     (c"ExecuteOn", execute_on),
+    (c"ExecuteAsync", execute_async),
+    (c"ExecuteOnAsync", execute_on_async),
+    (c"ExecuteSync", execute_sync),
+    (c"ExecuteSingle", execute_single),
+    (c"ExecuteScalar", execute_scalar),
+    (c"ExecuteColumn", execute_column),
+    (c"ExecuteColumnar", execute_columnar),
+    (c"ExecuteArrays", execute_arrays),
+    (c"ExecuteBatch", execute_batch),
+    (c"BulkWrite", bulk_write),
+    (c"EnsureConstraint", ensure_constraint),
+    (c"EnsureIndex", ensure_index),
+    (c"Schema", schema),
+    (c"Cursor", new_cursor),
+    (c"Paginate", new_pager),
+    (c"ReadOnly", read_only),
+    (c"Close", close),
+    (c"Ping", ping),
+    (c"IsHealthy", is_healthy),
+    (c"Health", health),
     (c"Tx", new_txn),
     (c"TxOn", new_txn_on)
 ]);
 
-#[lua_function]
-pub fn new_graph(l: lua::State) -> anyhow::Result<i32> {
-    let uri = l.check_string(1)?;
-    let user = l.check_string(2)?;
-    let password = l.check_string(3)?;
+/// Where and how a graph connects, kept for diagnostics and to compare
+/// registrations. Never holds the password itself.
+#[derive(Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub uri: String,
+    pub user: String,
+    /// Keyed hash, only good for telling whether two passwords match
+    pub password_hash: u64,
+    pub db: Option<String>,
+    pub fetch_size: Option<usize>,
+    pub max_connections: Option<usize>,
+}
+
+impl ConnectionInfo {
+    /// Names of the settings that differ between two connections.
+    pub fn differences(&self, other: &ConnectionInfo) -> Vec<&'static str> {
+        [
+            ("uri", self.uri != other.uri),
+            ("user", self.user != other.user),
+            ("password", self.password_hash != other.password_hash),
+            ("db", self.db != other.db),
+            ("fetch_size", self.fetch_size != other.fetch_size),
+            (
+                "max_connections",
+                self.max_connections != other.max_connections,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, differs)| differs.then_some(name))
+        .collect()
+    }
+}
 
+/// Reads `uri, user, password, options` starting at `index`.
+pub fn read_config(l: lua::State, index: i32) -> anyhow::Result<(Config, ConnectionInfo)> {
+    let uri = l.check_string(index)?;
+    let user = l.check_string(index + 1)?;
+    let password = l.check_string(index + 2)?;
+    let options = index + 3;
+
+    let mut info = ConnectionInfo {
+        uri: uri.clone(),
+        user: user.clone(),
+        password_hash: PASSWORD_HASHER.hash_one(&password),
+        db: None,
+        fetch_size: None,
+        max_connections: None,
+    };
     let mut config = neo4rs::ConfigBuilder::new()
         .uri(uri)
         .user(user)
         .password(password);
 
     // Catch bad parameter sets
-    if !l.is_none_or_nil(4) && !l.is_table(4) {
+    if !l.is_none_or_nil(options) && !l.is_table(options) {
         return Err(Error::msg("Last argument must be a table of options"));
     }
 
     // Parse the rest of the options
-    if l.is_table(4) {
-        l.get_field(4, c"db");
+    if l.is_table(options) {
+        l.get_field(options, c"db");
         if l.is_string(-1) {
             let db_str = l.get_string_unchecked(-1);
+            info.db = Some(db_str.clone());
             config = config.db(db_str)
         }
         l.pop_n(1);
 
-        l.get_field(4, c"fetch_size");
+        l.get_field(options, c"fetch_size");
         if l.is_number(-1) {
             let fetch_size = l.to_number(-1) as usize;
+            info.fetch_size = Some(fetch_size);
             config = config.fetch_size(fetch_size)
         }
         l.pop_n(1);
 
-        l.get_field(4, c"max_connections");
+        l.get_field(options, c"max_connections");
         if l.is_number(-1) {
             let max_connections = l.to_number(-1) as usize;
+            info.max_connections = Some(max_connections);
             config = config.max_connections(max_connections)
         }
         l.pop_n(1);
     }
 
-    let config = config.build()?;
-    l.push_struct::<LuaNeoGraph>(LuaNeoGraph::new(config)?);
+    Ok((config.build()?, info))
+}
+
+#[lua_function]
+pub fn new_graph(l: lua::State) -> anyhow::Result<i32> {
+    let (config, _) = read_config(l, 1)?;
+    let health_check = read_health_check(l, 4)?;
+
+    let graph = LuaNeoGraph::new(config)?;
+    if let Some(health_check) = health_check {
+        start_health_check(&graph, health_check);
+    }
+    l.push_struct::<LuaNeoGraph>(graph);
 
     Ok(1)
 }
 
+#[lua_function]
+pub fn read_only(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+
+    // Shares the connection pool with the handle it came from
+    l.push_struct::<LuaNeoGraph>(graph_container.share(true));
+
+    Ok(1)
+}
+
+/// Stops new work on the graph, waits for queries already running on it and
+/// then drops the connection pool. Read-only handles are refused, as the pool
+/// is shared with the writable handles they came from.
+#[lua_function]
+pub fn close(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    graph_container.check_writable("Close")?;
+
+    let callback = if l.is_none_or_nil(2) {
+        None
+    } else {
+        Some(l.check_function(2)?)
+    };
+
+    let state = graph_container.state.clone();
+    let graph = state
+        .graph
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take()
+        .ok_or_else(|| Error::msg("Graph closed"))?;
+    state.tracker.close();
+    state.closed.cancel();
+    registry::forget(&state);
+
+    runtime::run_async(async move {
+        state.tracker.wait().await;
+        drop(graph);
+        println!("[neo4j] Graph closed");
+
+        if let Some(callback) = callback {
+            wait_lua_tick(move |l| {
+                let _ = l.pcall_func_ref(callback, || {
+                    l.push_nil();
+                    1
+                });
+            });
+        }
+    });
+
+    Ok(0)
+}
+
 #[lua_function]
 pub fn new_txn(l: lua::State) -> anyhow::Result<i32> {
     let neo_graph = l.get_struct::<LuaNeoGraph>(1)?;
+    neo_graph.check_writable("Tx")?;
 
     // This is non async but it should be fine
     THREAD_WORKER.block_on(async {
-        let tx = neo_graph.graph.start_txn().await?;
+        let tx = neo_graph.graph()?.start_txn().await?;
 
         println!("Start txn");
 
-        l.push_struct::<LuaNeoTxn>(LuaNeoTxn(Arc::new(Mutex::new(Some(tx)))));
+        l.push_struct::<LuaNeoTxn>(LuaNeoTxn::new(neo_graph.state.clone(), tx));
 
         Ok(1)
     })
@@ -96,20 +331,80 @@ pub fn new_txn(l: lua::State) -> anyhow::Result<i32> {
 #[lua_function]
 pub fn new_txn_on(l: lua::State) -> anyhow::Result<i32> {
     let neo_graph = l.get_struct::<LuaNeoGraph>(1)?;
+    neo_graph.check_writable("TxOn")?;
 
     let db = l.check_string(2)?;
 
     // This is non async but it should be fine
     THREAD_WORKER.block_on(async {
-        let tx = neo_graph.graph.start_txn_on(db).await?;
+        let tx = neo_graph.graph()?.start_txn_on(db).await?;
         println!("Start txn");
 
-        l.push_struct::<LuaNeoTxn>(LuaNeoTxn(Arc::new(Mutex::new(Some(tx)))));
+        l.push_struct::<LuaNeoTxn>(LuaNeoTxn::new(neo_graph.state.clone(), tx));
         Ok(1)
     })
 }
 
-async fn handle_graph_execution(graph: Graph, query: Query) -> anyhow::Result<Vec<BoltMap>> {
+#[lua_function]
+pub fn new_cursor(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(3) && !l.is_table(3) {
+        return Err(Error::msg("Last argument must be a table of options"));
+    }
+
+    let mut page_size = DEFAULT_PAGE_SIZE;
+    if l.is_table(3) {
+        l.get_field(3, c"page_size");
+        if l.is_number(-1) {
+            page_size = l.to_number(-1).max(1.0) as usize;
+        }
+        l.pop_n(1);
+    }
+
+    // Fail straight away on a closed graph, the cursor checks again later
+    graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
+
+    l.push_struct::<LuaNeoCursor>(LuaNeoCursor::new(
+        graph_container.state.clone(),
+        query,
+        neo_query.columns(),
+        page_size,
+    ));
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn new_pager(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
+    let order_key = l.check_string(3)?;
+
+    if !l.is_number(4) || l.to_number(4) < 1.0 {
+        return Err(Error::msg(
+            "Paginate expects a positive page size as its third argument",
+        ));
+    }
+    let page_size = l.to_number(4) as usize;
+
+    graph_container.check_query(&neo_query.read().text)?;
+    graph_container.graph()?;
+    let pager = LuaNeoPager::new(
+        graph_container.state.clone(),
+        neo_query.read().clone(),
+        order_key,
+        page_size,
+    )?;
+    l.push_struct::<LuaNeoPager>(pager);
+
+    Ok(1)
+}
+
+pub async fn handle_graph_execution(graph: Graph, query: Query) -> anyhow::Result<Vec<BoltMap>> {
     let result = async {
         let mut results = graph.execute(query).await?;
         let mut output = Vec::new();
@@ -151,20 +446,27 @@ async fn handle_graph_execution_on(
 pub fn execute(l: lua::State) -> anyhow::Result<i32> {
     let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
     let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
-    let callback = l.check_function(3)?;
 
-    let graph = graph_container.graph.clone();
-    let arc_query = neo_query.0.clone();
+    let graph = graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
 
-    runtime::run_async(async move {
-        let results = {
-            let query = (*arc_query).clone();
+    // Without a callback, hand back a promise instead
+    if l.is_none_or_nil(3) {
+        let promise =
+            LuaNeoPromise::spawn(graph_container.track(handle_graph_execution(graph, query)));
+        l.push_struct::<LuaNeoPromise>(promise);
+        return Ok(1);
+    }
+    let callback = l.check_function(3)?;
+    let columns = neo_query.columns();
 
-            handle_graph_execution(graph, query).await
-        };
+    runtime::run_async(graph_container.track(async move {
+        let results = handle_graph_execution(graph, query)
+            .await
+            .map(|rows| ResultSet::new(rows, columns));
 
         dispatch_callback(callback, results);
-    });
+    }));
 
     Ok(0)
 }
@@ -174,20 +476,210 @@ pub fn execute_on(l: lua::State) -> anyhow::Result<i32> {
     let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
     let db = l.check_string(2)?;
     let neo_query = l.get_struct::<LuaNeoQuery>(3)?;
-    let callback = l.check_function(4)?;
 
-    let graph = graph_container.graph.clone();
-    let arc_query = neo_query.0.clone();
+    // The other database could be `system`, where the read check means nothing
+    graph_container.check_writable("ExecuteOn")?;
 
-    runtime::run_async(async move {
-        let results = {
-            let query = (*arc_query).clone();
+    let graph = graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
 
-            handle_graph_execution_on(&db, graph, query).await
-        };
+    if l.is_none_or_nil(4) {
+        let promise = LuaNeoPromise::spawn(
+            graph_container
+                .track(async move { handle_graph_execution_on(&db, graph, query).await }),
+        );
+        l.push_struct::<LuaNeoPromise>(promise);
+        return Ok(1);
+    }
+    let callback = l.check_function(4)?;
+    let columns = neo_query.columns();
+
+    runtime::run_async(graph_container.track(async move {
+        let results = handle_graph_execution_on(&db, graph, query)
+            .await
+            .map(|rows| ResultSet::new(rows, columns));
 
         dispatch_callback(callback, results);
+    }));
+
+    Ok(0)
+}
+
+#[lua_function]
+pub fn execute_async(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
+
+    let graph = graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
+
+    let promise = LuaNeoPromise::spawn(graph_container.track(handle_graph_execution(graph, query)));
+    l.push_struct::<LuaNeoPromise>(promise);
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn execute_on_async(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let db = l.check_string(2)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(3)?;
+
+    // The other database could be `system`, where the read check means nothing
+    graph_container.check_writable("ExecuteOnAsync")?;
+
+    let graph = graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
+
+    let promise = LuaNeoPromise::spawn(
+        graph_container.track(async move { handle_graph_execution_on(&db, graph, query).await }),
+    );
+    l.push_struct::<LuaNeoPromise>(promise);
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn execute_sync(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
+
+    if !l.is_number(3) {
+        return Err(Error::msg(
+            "ExecuteSync expects a timeout in milliseconds as its second argument",
+        ));
+    }
+    let timeout_ms = l.to_number(3).max(0.0) as u64;
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(4) && !l.is_table(4) {
+        return Err(Error::msg("Last argument must be a table of options"));
+    }
+
+    let mut allow_ticking = false;
+    if l.is_table(4) {
+        l.get_field(4, c"allow_ticking");
+        if l.lua_type(-1) == LUA_TBOOLEAN {
+            allow_ticking = l.check_boolean(-1)?;
+        }
+        l.pop_n(1);
+    }
+
+    // Blocking freezes the game, which is only acceptable while the server starts
+    if !allow_ticking && runtime::is_server_ticking(l) {
+        return Err(Error::msg(
+            "ExecuteSync blocks the server and can only be used before it starts ticking; \
+             pass { allow_ticking = true } to override",
+        ));
+    }
+
+    let graph = graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
+
+    let timeout = std::time::Duration::from_millis(timeout_ms);
+    let result = runtime::block_on(async {
+        tokio::time::timeout(timeout, handle_graph_execution(graph, query)).await
     });
 
+    match result {
+        Ok(Ok(rows)) => {
+            push_rows(l, &rows)?;
+            l.push_nil();
+        }
+        Ok(Err(err)) => {
+            l.push_nil();
+            l.push_string(&err.to_string());
+        }
+        Err(_) => {
+            l.push_nil();
+            l.push_string(&format!("ExecuteSync timed out after {} ms", timeout_ms));
+        }
+    }
+
+    Ok(2)
+}
+
+#[lua_function]
+pub fn execute_batch(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let queries = read_queries(l, graph_container, 2)?;
+    let callback = l.check_function(3)?;
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(4) && !l.is_table(4) {
+        return Err(Error::msg("Last argument must be a table of options"));
+    }
+
+    let mut atomic = true;
+    if l.is_table(4) {
+        l.get_field(4, c"atomic");
+        if l.lua_type(-1) == LUA_TBOOLEAN {
+            atomic = l.check_boolean(-1)?;
+        }
+        l.pop_n(1);
+    }
+
+    let graph = graph_container.graph()?;
+
+    runtime::run_async(graph_container.track(async move {
+        let outcome = if atomic {
+            run_atomic(graph, queries).await
+        } else {
+            run_independent(graph, queries).await
+        };
+
+        dispatch_batch_callback(callback, outcome);
+    }));
+
+    Ok(0)
+}
+
+fn execute_with_shape(
+    l: lua::State,
+    shape: ResultShape,
+    callback_index: i32,
+) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
+    let callback = l.check_function(callback_index)?;
+
+    let graph = graph_container.graph()?;
+    let query = graph_container.prepare(&neo_query)?;
+    let columns = neo_query.columns();
+
+    runtime::run_async(graph_container.track(async move {
+        let results = handle_graph_execution(graph, query)
+            .await
+            .map(|rows| ResultSet::new(rows, columns));
+
+        dispatch_shaped_callback(callback, shape, results);
+    }));
+
     Ok(0)
 }
+
+#[lua_function]
+pub fn execute_single(l: lua::State) -> anyhow::Result<i32> {
+    execute_with_shape(l, ResultShape::Single, 3)
+}
+
+#[lua_function]
+pub fn execute_scalar(l: lua::State) -> anyhow::Result<i32> {
+    execute_with_shape(l, ResultShape::Scalar, 3)
+}
+
+#[lua_function]
+pub fn execute_column(l: lua::State) -> anyhow::Result<i32> {
+    let column = l.check_string(3)?;
+    execute_with_shape(l, ResultShape::Column(column), 4)
+}
+
+#[lua_function]
+pub fn execute_columnar(l: lua::State) -> anyhow::Result<i32> {
+    execute_with_shape(l, ResultShape::Columnar, 3)
+}
+
+#[lua_function]
+pub fn execute_arrays(l: lua::State) -> anyhow::Result<i32> {
+    execute_with_shape(l, ResultShape::Arrays, 3)
+}
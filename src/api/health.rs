@@ -0,0 +1,375 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, anyhow};
+use gmod::{lua, lua_function, wait_lua_tick};
+use neo4rs::{Graph, query};
+use tokio::time::MissedTickBehavior;
+
+use crate::api::graph::{GraphState, LuaNeoGraph, handle_graph_execution};
+use crate::runtime;
+
+pub const DEFAULT_FAILURES: u32 = 3;
+/// Longest a ping may take by default, shortened to the interval if that is
+/// shorter
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `options.health_check` of `neo4j.Graph` and `neo4j.Register`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    /// Consecutive failed pings before the graph counts as unhealthy
+    pub failures: u32,
+    /// A ping that takes longer counts as failed
+    pub timeout: Duration,
+}
+
+pub struct HealthStatus {
+    pub healthy: bool,
+    pub failures: u32,
+    pub threshold: u32,
+    /// Used by `Ping` too, so a hung connection always gets an answer
+    pub timeout: Duration,
+    pub latency: Option<Duration>,
+    pub error: Option<String>,
+}
+
+impl Default for HealthStatus {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            failures: 0,
+            // Without a health check every failed ping counts
+            threshold: 1,
+            timeout: DEFAULT_TIMEOUT,
+            latency: None,
+            error: None,
+        }
+    }
+}
+
+impl HealthStatus {
+    fn record(&mut self, result: &anyhow::Result<PingInfo>) {
+        match result {
+            Ok(info) => {
+                if !self.healthy {
+                    println!("[neo4j] Graph is reachable again");
+                }
+                self.healthy = true;
+                self.failures = 0;
+                self.latency = Some(info.latency);
+                self.error = None;
+            }
+            Err(err) => {
+                self.failures += 1;
+                self.error = Some(err.to_string());
+                if self.healthy && self.failures >= self.threshold {
+                    eprintln!(
+                        "[neo4j] Graph is unhealthy after {} failed pings: {}",
+                        self.failures, err
+                    );
+                    self.healthy = false;
+                }
+            }
+        }
+    }
+}
+
+pub struct PingInfo {
+    pub latency: Duration,
+    pub version: String,
+    pub edition: String,
+}
+
+/// Pings the graph, failing if there is no answer within `timeout`.
+pub async fn run_ping(graph: Graph, timeout: Duration) -> anyhow::Result<PingInfo> {
+    let started = Instant::now();
+    let rows = tokio::time::timeout(
+        timeout,
+        handle_graph_execution(
+            graph,
+            query(
+                "CALL dbms.components() YIELD name, versions, edition RETURN name, versions, \
+                 edition",
+            ),
+        ),
+    )
+    .await
+    .map_err(|_| anyhow!("Ping timed out after {:.1}s", timeout.as_secs_f64()))??;
+    let latency = started.elapsed();
+
+    let row = rows
+        .iter()
+        .find(|row| {
+            row.get::<String>("name")
+                .is_ok_and(|name| name == "Neo4j Kernel")
+        })
+        .or(rows.first())
+        .ok_or_else(|| Error::msg("dbms.components() returned no rows"))?;
+    let versions: Vec<String> = row.get("versions")?;
+
+    Ok(PingInfo {
+        latency,
+        version: versions.into_iter().next().unwrap_or_default(),
+        edition: row.get("edition")?,
+    })
+}
+
+fn record(state: &GraphState, result: &anyhow::Result<PingInfo>) {
+    state
+        .health
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .record(result);
+}
+
+pub fn read_health_check(l: lua::State, options: i32) -> anyhow::Result<Option<HealthCheck>> {
+    if !l.is_table(options) {
+        return Ok(None);
+    }
+
+    l.get_field(options, c"health_check");
+    if l.is_none_or_nil(-1) {
+        l.pop_n(1);
+        return Ok(None);
+    }
+    if !l.is_table(-1) {
+        l.pop_n(1);
+        return Err(Error::msg("health_check must be a table of options"));
+    }
+
+    l.get_field(-1, c"interval");
+    let interval = l.is_number(-1).then(|| l.to_number(-1));
+    l.pop_n(1);
+
+    l.get_field(-1, c"failures");
+    let failures = l.is_number(-1).then(|| l.to_number(-1));
+    l.pop_n(1);
+
+    l.get_field(-1, c"timeout");
+    let timeout = l.is_number(-1).then(|| l.to_number(-1));
+    l.pop_n(2);
+
+    let interval = match interval {
+        Some(interval) if interval > 0.0 => Duration::from_secs_f64(interval),
+        _ => {
+            return Err(Error::msg(
+                "health_check needs an interval in seconds, e.g. { interval = 30 }",
+            ));
+        }
+    };
+    let failures = match failures {
+        None => DEFAULT_FAILURES,
+        Some(failures) if failures >= 1.0 => failures as u32,
+        Some(failures) => {
+            return Err(anyhow!(
+                "health_check failures must be at least 1, got {}",
+                failures
+            ));
+        }
+    };
+
+    let timeout = match timeout {
+        None => DEFAULT_TIMEOUT.min(interval),
+        Some(timeout) if timeout > 0.0 => Duration::from_secs_f64(timeout),
+        Some(timeout) => {
+            return Err(anyhow!(
+                "health_check timeout must be more than 0 seconds, got {}",
+                timeout
+            ));
+        }
+    };
+
+    Ok(Some(HealthCheck {
+        interval,
+        failures,
+        timeout,
+    }))
+}
+
+/// Pings the graph every interval until it is closed, or until every handle
+/// to it has been dropped. Only a weak reference is kept, so the check does
+/// not keep the pool alive by itself.
+pub fn start_health_check(graph: &LuaNeoGraph, check: HealthCheck) {
+    {
+        let mut status = graph
+            .state
+            .health
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.threshold = check.failures;
+        status.timeout = check.timeout;
+    }
+
+    let state: Weak<GraphState> = Arc::downgrade(&graph.state);
+    let closed = graph.state.closed.clone();
+    runtime::run_background(async move {
+        let mut interval = tokio::time::interval(check.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes straight away, the graph has just connected
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = closed.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            let Some(graph) = state.upgrade().and_then(|state| state.graph().ok()) else {
+                break;
+            };
+
+            let result = tokio::select! {
+                _ = closed.cancelled() => break,
+                result = run_ping(graph, check.timeout) => result,
+            };
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            record(&state, &result);
+        }
+    });
+}
+
+#[lua_function]
+pub fn ping(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let callback = l.check_function(2)?;
+
+    let graph = graph_container.graph()?;
+    let state = graph_container.state.clone();
+    let timeout = state
+        .health
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .timeout;
+    runtime::run_async(graph_container.track(async move {
+        let result = run_ping(graph, timeout).await;
+        record(&state, &result);
+
+        wait_lua_tick(move |l| {
+            let _ = l.pcall_func_ref(callback, || match &result {
+                Ok(info) => {
+                    l.push_nil();
+
+                    l.new_table();
+                    l.push_string("latency");
+                    l.push_number(info.latency.as_secs_f64() * 1000.0);
+                    l.raw_set_table(-3);
+
+                    l.push_string("version");
+                    l.push_string(&info.version);
+                    l.raw_set_table(-3);
+
+                    l.push_string("edition");
+                    l.push_string(&info.edition);
+                    l.raw_set_table(-3);
+                    1
+                }
+                Err(err) => {
+                    l.push_string(&err.to_string());
+                    l.push_nil();
+                    1
+                }
+            });
+        });
+    }));
+
+    Ok(0)
+}
+
+#[lua_function]
+pub fn is_healthy(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let state = &graph_container.state;
+
+    let healthy = !state.closed.is_cancelled()
+        && state
+            .health
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .healthy;
+
+    l.push_boolean(healthy);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn health(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let state = &graph_container.state;
+    let closed = state.closed.is_cancelled();
+    let health = state
+        .health
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    l.new_table();
+    l.push_string("healthy");
+    l.push_boolean(!closed && health.healthy);
+    l.raw_set_table(-3);
+
+    l.push_string("closed");
+    l.push_boolean(closed);
+    l.raw_set_table(-3);
+
+    l.push_string("failures");
+    l.push_number(health.failures);
+    l.raw_set_table(-3);
+
+    if let Some(latency) = health.latency {
+        l.push_string("latency");
+        l.push_number(latency.as_secs_f64() * 1000.0);
+        l.raw_set_table(-3);
+    }
+
+    if let Some(error) = &health.error {
+        l.push_string("error");
+        l.push_string(error);
+        l.raw_set_table(-3);
+    }
+
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pong() -> anyhow::Result<PingInfo> {
+        Ok(PingInfo {
+            latency: Duration::from_millis(5),
+            version: "5.0.0".into(),
+            edition: "community".into(),
+        })
+    }
+
+    #[test]
+    fn unhealthy_after_threshold_failures() {
+        let mut status = HealthStatus {
+            threshold: 3,
+            ..Default::default()
+        };
+
+        status.record(&Err(Error::msg("refused")));
+        status.record(&Err(Error::msg("refused")));
+        assert!(status.healthy);
+        assert_eq!(status.failures, 2);
+
+        status.record(&Err(Error::msg("timed out")));
+        assert!(!status.healthy);
+        assert_eq!(status.error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn success_resets_the_status() {
+        let mut status = HealthStatus::default();
+        status.record(&Err(Error::msg("refused")));
+        assert!(!status.healthy);
+
+        status.record(&pong());
+        assert!(status.healthy);
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.latency, Some(Duration::from_millis(5)));
+        assert!(status.error.is_none());
+    }
+}
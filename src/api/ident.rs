@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use anyhow::{Error, anyhow};
+use gmod::rstruct::RStruct;
+use gmod::{LUA_TBOOLEAN, LUA_TSTRING, LUA_TUSERDATA, lua, lua_function, register_lua_rstruct};
+
+use crate::cypher::{escape_identifier, validate_identifier, validate_special_identifier};
+use crate::mapping::lua_type_name;
+
+/// A validated and escaped identifier, ready to be placed in query text.
+pub struct LuaNeoIdent(pub String);
+
+impl LuaNeoIdent {
+    pub fn new(name: &str) -> anyhow::Result<Self> {
+        validate_identifier(name)?;
+        Ok(Self(escape_identifier(name)))
+    }
+
+    /// For names with spaces or other characters, which callers have to ask
+    /// for with `{ allow_special = true }`.
+    pub fn new_special(name: &str) -> anyhow::Result<Self> {
+        validate_special_identifier(name)?;
+        Ok(Self(escape_identifier(name)))
+    }
+}
+
+register_lua_rstruct!(LuaNeoIdent, c"Neo4jIdent", &[
+    (c"__tostring", to_string)
+]);
+
+#[lua_function]
+pub fn ident(l: lua::State) -> anyhow::Result<i32> {
+    let name = l.check_string(1)?;
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(2) && !l.is_table(2) {
+        return Err(Error::msg("Last argument must be a table of options"));
+    }
+
+    let mut allow_special = false;
+    if l.is_table(2) {
+        l.get_field(2, c"allow_special");
+        if l.lua_type(-1) == LUA_TBOOLEAN {
+            allow_special = l.check_boolean(-1)?;
+        }
+        l.pop_n(1);
+    }
+
+    let ident = if allow_special {
+        LuaNeoIdent::new_special(&name)?
+    } else {
+        LuaNeoIdent::new(&name)?
+    };
+    l.push_struct::<LuaNeoIdent>(ident);
+    Ok(1)
+}
+
+/// `neo4j.Label("A", "B")` gives `` `A`:`B` `` for matching several labels.
+/// Each label is a string or a `Neo4jIdent`.
+#[lua_function]
+pub fn label(l: lua::State) -> anyhow::Result<i32> {
+    let count = l.get_top();
+    if count == 0 {
+        return Err(Error::msg("Label expects at least one name"));
+    }
+
+    let mut labels = Vec::new();
+    for i in 1..=count {
+        if l.lua_type(i) == LUA_TUSERDATA {
+            let ident = l
+                .get_struct::<LuaNeoIdent>(i)
+                .map_err(|_| anyhow!("Argument {} is not a Neo4jIdent", i))?;
+            labels.push(ident.0.clone());
+        } else {
+            labels.push(LuaNeoIdent::new(&l.check_string(i)?)?.0);
+        }
+    }
+
+    l.push_struct::<LuaNeoIdent>(LuaNeoIdent(labels.join(":")));
+    Ok(1)
+}
+
+#[lua_function]
+pub fn to_string(l: lua::State) -> anyhow::Result<i32> {
+    let ident = l.get_struct::<LuaNeoIdent>(1)?;
+
+    l.push_string(&format!("Neo4jIdent [{}]", ident.0));
+    Ok(1)
+}
+
+/// Reads a table of template values. Plain strings are escaped like
+/// `neo4j.Ident`.
+pub fn template_values(l: lua::State, index: i32) -> anyhow::Result<HashMap<String, String>> {
+    if !l.is_table(index) {
+        return Err(Error::msg("Expected a table of template values"));
+    }
+
+    let mut values = HashMap::new();
+    unsafe {
+        l.push_nil();
+        while l.next(index) != 0 {
+            let key_type = l.lua_type(-2);
+            if key_type != LUA_TSTRING {
+                l.pop_n(2);
+                return Err(anyhow!(
+                    "Template names must be strings, got {}",
+                    lua_type_name(key_type)
+                ));
+            }
+            let key = l.get_string_unchecked(-2);
+
+            let value = match l.lua_type(-1) {
+                LUA_TSTRING => LuaNeoIdent::new(&l.get_string_unchecked(-1)).map(|ident| ident.0),
+                LUA_TUSERDATA => l
+                    .get_struct::<LuaNeoIdent>(l.get_top())
+                    .map(|ident| ident.0.clone())
+                    .map_err(|_| anyhow!("expected a Neo4jIdent")),
+                value_type => Err(anyhow!(
+                    "expected a Neo4jIdent or string, got {}",
+                    lua_type_name(value_type)
+                )),
+            };
+            l.pop_n(1);
+
+            match value {
+                Ok(value) => values.insert(key, value),
+                Err(err) => {
+                    l.pop_n(1);
+                    return Err(anyhow!("Invalid template value '{}': {}", key, err));
+                }
+            };
+        }
+    }
+
+    Ok(values)
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+
+use anyhow::{Error, anyhow};
+use gmod::{LUA_TBOOLEAN, LuaReference, lua, lua_function, wait_lua_tick};
+use neo4rs::{Graph, query};
+
+use crate::api::allowlist::check_startup;
+use crate::api::graph::{LuaNeoGraph, handle_graph_execution};
+use crate::cypher::{is_schema_statement, split_statements};
+use crate::files::{checksum, read_cypher_files};
+use crate::runtime;
+
+/// A `V<version>__<description>.cypher` file.
+pub struct Migration {
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    pub statements: Vec<String>,
+}
+
+struct AppliedMigration {
+    name: String,
+    checksum: String,
+}
+
+#[derive(Default)]
+pub struct MigrationReport {
+    /// Migrations applied by this run
+    pub applied: Vec<String>,
+    /// Migrations left to apply
+    pub pending: Vec<String>,
+}
+
+fn parse_version(name: &str) -> Option<i64> {
+    let stem = name.strip_suffix(".cypher")?;
+    let (version, description) = stem.strip_prefix('V')?.split_once("__")?;
+    if description.is_empty() || !version.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    version.parse().ok()
+}
+
+pub fn read_migrations(dir: &str) -> anyhow::Result<Vec<Migration>> {
+    let mut migrations: Vec<Migration> = Vec::new();
+    for file in read_cypher_files(dir)? {
+        let version = parse_version(&file.name).ok_or_else(|| {
+            anyhow!(
+                "{}: migration files must be named like V001__description.cypher",
+                file.name
+            )
+        })?;
+
+        if let Some(existing) = migrations.iter().find(|m| m.version == version) {
+            return Err(anyhow!(
+                "{}: version {} is already used by {}",
+                file.name,
+                version,
+                existing.name
+            ));
+        }
+
+        let statements = split_statements(&file.contents);
+        if statements.is_empty() {
+            return Err(anyhow!("{}: migration has no statements", file.name));
+        }
+
+        migrations.push(Migration {
+            version,
+            name: file.name.trim_end_matches(".cypher").to_string(),
+            checksum: checksum(&file.contents),
+            statements,
+        });
+    }
+
+    migrations.sort_by_key(|migration| migration.version);
+    Ok(migrations)
+}
+
+async fn read_applied(graph: &Graph) -> anyhow::Result<HashMap<i64, AppliedMigration>> {
+    let rows = handle_graph_execution(
+        graph.clone(),
+        query(
+            "MATCH (m:__Migration) RETURN m.version AS version, m.name AS name, \
+             m.checksum AS checksum",
+        ),
+    )
+    .await?;
+
+    let mut applied = HashMap::new();
+    for row in rows {
+        let version: i64 = row.get("version")?;
+        applied.insert(
+            version,
+            AppliedMigration {
+                name: row.get("name")?,
+                checksum: row.get("checksum")?,
+            },
+        );
+    }
+
+    Ok(applied)
+}
+
+/// Works out which migrations still have to run, refusing to go on if the
+/// applied ones no longer match the files.
+fn pending_migrations(
+    migrations: Vec<Migration>,
+    applied: &HashMap<i64, AppliedMigration>,
+) -> anyhow::Result<Vec<Migration>> {
+    let mut problems = Vec::new();
+    for (version, applied) in applied {
+        match migrations.iter().find(|m| m.version == *version) {
+            None => problems.push(format!(
+                "{} was applied but its file is missing",
+                applied.name
+            )),
+            Some(migration) if migration.checksum != applied.checksum => problems.push(format!(
+                "{} was changed after it was applied",
+                migration.name
+            )),
+            Some(_) => {}
+        }
+    }
+
+    let latest = applied.keys().max().copied();
+    let pending: Vec<Migration> = migrations
+        .into_iter()
+        .filter(|migration| !applied.contains_key(&migration.version))
+        .collect();
+    if let Some(latest) = latest {
+        for migration in pending.iter().filter(|m| m.version < latest) {
+            problems.push(format!(
+                "{} is older than the latest applied version {}",
+                migration.name, latest
+            ));
+        }
+    }
+
+    if !problems.is_empty() {
+        problems.sort();
+        return Err(anyhow!("Refusing to migrate: {}", problems.join("; ")));
+    }
+
+    Ok(pending)
+}
+
+/// Attempts at recording a schema migration once it has been committed.
+const RECORD_ATTEMPTS: usize = 3;
+
+/// Unique names keep records idempotent and let only one server hold the
+/// lock at a time.
+async fn ensure_migration_constraints(graph: &Graph) -> anyhow::Result<()> {
+    graph
+        .run(query(
+            "CREATE CONSTRAINT __migration_name IF NOT EXISTS \
+             FOR (m:__Migration) REQUIRE m.name IS UNIQUE",
+        ))
+        .await?;
+    graph
+        .run(query(
+            "CREATE CONSTRAINT __migration_lock IF NOT EXISTS \
+             FOR (l:__MigrationLock) REQUIRE l.id IS UNIQUE",
+        ))
+        .await?;
+    Ok(())
+}
+
+/// Takes the migration lock, failing if another server holds it. Returns
+/// the owner token needed to release it.
+async fn lock(graph: &Graph) -> anyhow::Result<String> {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let owner = format!("{}-{}", std::process::id(), nanos);
+
+    let created = graph
+        .run(
+            query("CREATE (:__MigrationLock {id: 'migrate', owner: $owner, lockedAt: datetime()})")
+                .param("owner", owner.as_str()),
+        )
+        .await;
+    if let Err(err) = created {
+        let holder = handle_graph_execution(
+            graph.clone(),
+            query(
+                "MATCH (l:__MigrationLock {id: 'migrate'}) \
+                 RETURN toString(l.lockedAt) AS lockedAt",
+            ),
+        )
+        .await
+        .ok()
+        .and_then(|rows| rows.first()?.get::<String>("lockedAt").ok());
+
+        return Err(match holder {
+            Some(locked_at) => anyhow!(
+                "Another server is migrating, locked since {}. If no migration is running, \
+                 delete the (:__MigrationLock) node and try again",
+                locked_at
+            ),
+            None => anyhow!("Could not take the migration lock: {}", err),
+        });
+    }
+
+    Ok(owner)
+}
+
+async fn unlock(graph: &Graph, owner: &str) {
+    let released = graph
+        .run(
+            query("MATCH (l:__MigrationLock {id: 'migrate', owner: $owner}) DELETE l")
+                .param("owner", owner),
+        )
+        .await;
+    if let Err(err) = released {
+        eprintln!("[neo4j] Could not release the migration lock: {}", err);
+    }
+}
+
+async fn apply(graph: &Graph, migration: &Migration) -> anyhow::Result<()> {
+    // MERGE on the unique name, so recording twice is harmless
+    let record = query(
+        "MERGE (m:__Migration {name: $name}) \
+         ON CREATE SET m.version = $version, m.checksum = $checksum, m.appliedAt = datetime()",
+    )
+    .param("version", migration.version)
+    .param("name", migration.name.as_str())
+    .param("checksum", migration.checksum.as_str());
+
+    // Index and constraint changes can't share a transaction with writes, so
+    // those migrations are recorded once they are committed
+    let has_schema = migration
+        .statements
+        .iter()
+        .any(|statement| is_schema_statement(statement));
+
+    let mut txn = graph.start_txn().await?;
+    for (i, statement) in migration.statements.iter().enumerate() {
+        if let Err(err) = txn.run(query(statement)).await {
+            if let Err(rollback_err) = txn.rollback().await {
+                eprintln!("Rollback failed after error {}: {}", err, rollback_err);
+            }
+            return Err(anyhow!(
+                "{} failed at statement {}: {}",
+                migration.name,
+                i + 1,
+                err
+            ));
+        }
+    }
+
+    if has_schema {
+        txn.commit().await?;
+
+        // A failed record would make the migration run again next time
+        let mut attempt = 1;
+        while let Err(err) = graph.run(record.clone()).await {
+            if attempt == RECORD_ATTEMPTS {
+                return Err(anyhow!(
+                    "{} was applied but could not be recorded: {}",
+                    migration.name,
+                    err
+                ));
+            }
+            attempt += 1;
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+    } else {
+        txn.run(record).await?;
+        txn.commit().await?;
+    }
+
+    Ok(())
+}
+
+async fn run_migrations(
+    graph: Graph,
+    migrations: Vec<Migration>,
+    dry_run: bool,
+    report: &mut MigrationReport,
+) -> anyhow::Result<()> {
+    if dry_run {
+        let applied = read_applied(&graph).await?;
+        let pending = pending_migrations(migrations, &applied)?;
+        report.pending = pending.iter().map(|m| m.name.clone()).collect();
+        return Ok(());
+    }
+
+    ensure_migration_constraints(&graph).await?;
+    let owner = lock(&graph).await?;
+    let result = apply_pending(&graph, migrations, report).await;
+    unlock(&graph, &owner).await;
+
+    result
+}
+
+/// Applies what is still pending, once the lock is held.
+async fn apply_pending(
+    graph: &Graph,
+    migrations: Vec<Migration>,
+    report: &mut MigrationReport,
+) -> anyhow::Result<()> {
+    // Read after locking, another server may have just finished
+    let applied = read_applied(graph).await?;
+    let pending = pending_migrations(migrations, &applied)?;
+    report.pending = pending.iter().map(|m| m.name.clone()).collect();
+
+    for migration in &pending {
+        apply(graph, migration).await?;
+        println!("[neo4j] Applied migration {}", migration.name);
+
+        report.pending.remove(0);
+        report.applied.push(migration.name.clone());
+    }
+
+    Ok(())
+}
+
+fn push_names(l: lua::State, names: &[String]) {
+    l.new_table();
+    for (i, name) in names.iter().enumerate() {
+        l.push_string(name);
+        l.raw_seti(-2, i as i32 + 1);
+    }
+}
+
+fn dispatch_report(callback: LuaReference, result: anyhow::Result<()>, report: MigrationReport) {
+    wait_lua_tick(move |l| {
+        let _ = l.pcall_func_ref(callback, || {
+            match &result {
+                Ok(()) => l.push_nil(),
+                Err(err) => l.push_string(&err.to_string()),
+            }
+
+            l.new_table();
+            l.push_string("applied");
+            push_names(l, &report.applied);
+            l.raw_set_table(-3);
+
+            l.push_string("pending");
+            push_names(l, &report.pending);
+            l.raw_set_table(-3);
+
+            1
+        });
+    });
+}
+
+#[lua_function]
+pub fn migrate(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let dir = l.check_string(2)?;
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(4) && !l.is_table(4) {
+        return Err(Error::msg("Last argument must be a table of options"));
+    }
+
+    let mut dry_run = false;
+    if l.is_table(4) {
+        l.get_field(4, c"dry_run");
+        if l.lua_type(-1) == LUA_TBOOLEAN {
+            dry_run = l.check_boolean(-1)?;
+        }
+        l.pop_n(1);
+    }
+    if !dry_run {
+        graph_container.check_writable("Migrate")?;
+        // Migrations run directly, outside the allowlist, so strict mode keeps
+        // them to startup
+        check_startup(l, "Migrate")?;
+    }
+
+    // Bad files are reported straight away, before anything is run
+    let migrations = read_migrations(&dir)?;
+    let callback = l.check_function(3)?;
+    let graph = graph_container.graph()?;
+
+    runtime::run_async(graph_container.track(async move {
+        let mut report = MigrationReport::default();
+        let result = run_migrations(graph, migrations, dry_run, &mut report).await;
+
+        dispatch_report(callback, result, report);
+    }));
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migration(version: i64, contents: &str) -> Migration {
+        Migration {
+            version,
+            name: format!("V{:03}__test", version),
+            checksum: checksum(contents),
+            statements: split_statements(contents),
+        }
+    }
+
+    fn applied(migrations: &[Migration]) -> HashMap<i64, AppliedMigration> {
+        migrations
+            .iter()
+            .map(|migration| {
+                (
+                    migration.version,
+                    AppliedMigration {
+                        name: migration.name.clone(),
+                        checksum: migration.checksum.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn versions_come_from_the_file_name() {
+        assert_eq!(parse_version("V001__create_users.cypher"), Some(1));
+        assert_eq!(parse_version("V20240101__x.cypher"), Some(20240101));
+        assert_eq!(parse_version("V001__.cypher"), None);
+        assert_eq!(parse_version("V001_create.cypher"), None);
+        assert_eq!(parse_version("v001__create.cypher"), None);
+        assert_eq!(parse_version("V-1__create.cypher"), None);
+        assert_eq!(parse_version("V001__create.cql"), None);
+    }
+
+    #[test]
+    fn only_new_migrations_are_pending() {
+        let files = vec![migration(1, "CREATE (:A)"), migration(2, "CREATE (:B)")];
+        let applied = applied(&files[..1]);
+
+        let pending = pending_migrations(files, &applied).unwrap();
+        assert_eq!(pending.iter().map(|m| m.version).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn changed_migrations_are_refused() {
+        let applied = applied(&[migration(1, "CREATE (:A)")]);
+
+        let err = pending_migrations(vec![migration(1, "CREATE (:Changed)")], &applied)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("V001__test was changed after it was applied"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn missing_and_older_migrations_are_refused() {
+        let applied = applied(&[migration(2, "CREATE (:B)")]);
+
+        let err = pending_migrations(vec![migration(1, "CREATE (:A)")], &applied)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("V002__test was applied but its file is missing"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("V001__test is older than the latest applied version 2"),
+            "{}",
+            err
+        );
+    }
+}
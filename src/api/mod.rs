@@ -1,4 +1,18 @@
+pub mod allowlist;
+pub mod batch;
+pub mod builder;
+pub mod bulk;
+pub mod cursor;
 pub mod graph;
+pub mod health;
+pub mod ident;
+pub mod migrate;
+pub mod model;
+pub mod named;
+pub mod pager;
+pub mod promise;
 pub mod query;
+pub mod registry;
 pub mod result;
+pub mod schema;
 pub mod transaction;
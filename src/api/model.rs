@@ -0,0 +1,567 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Error, anyhow};
+use gmod::rstruct::RStruct;
+use gmod::{LUA_TSTRING, lua, lua_function, register_lua_rstruct};
+use lazy_static::lazy_static;
+use neo4rs::{BoltFloat, BoltMap, BoltString, BoltType};
+
+use crate::api::allowlist::check_startup;
+use crate::api::query::{LuaNeoQuery, NeoQuery};
+use crate::cypher::{escape_identifier, validate_identifier};
+use crate::mapping::{lua_table_to_boltmap, lua_type_name, lua_value_to_bolttype};
+
+/// Declared type of a model property. Values are converted to it on save.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PropType {
+    Any,
+    String,
+    Number,
+    Integer,
+    Boolean,
+    List,
+    Map,
+}
+
+impl PropType {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "any" => Some(Self::Any),
+            "string" => Some(Self::String),
+            "number" | "float" => Some(Self::Number),
+            "integer" | "int" => Some(Self::Integer),
+            "boolean" | "bool" => Some(Self::Boolean),
+            "list" | "array" => Some(Self::List),
+            "map" | "table" => Some(Self::Map),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Any => "any",
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Boolean => "boolean",
+            Self::List => "list",
+            Self::Map => "map",
+        }
+    }
+
+    pub fn coerce(self, value: BoltType, property: &str) -> anyhow::Result<BoltType> {
+        let value = match (self, value) {
+            (Self::Any, value) => value,
+            (Self::String, BoltType::String(value)) => BoltType::String(value),
+            // Numbers are stored as their text. This can't restore precision
+            // Lua already lost, so ids such as SteamID64 must be strings in Lua
+            (Self::String, BoltType::Integer(value)) => {
+                BoltType::String(BoltString::from(value.value.to_string()))
+            }
+            (Self::String, BoltType::Float(value)) => {
+                BoltType::String(BoltString::from(value.value.to_string()))
+            }
+            (Self::Number, BoltType::Float(value)) => BoltType::Float(value),
+            (Self::Number, BoltType::Integer(value)) => {
+                BoltType::Float(BoltFloat::new(value.value as f64))
+            }
+            (Self::Integer, BoltType::Integer(value)) => BoltType::Integer(value),
+            (Self::Boolean, BoltType::Boolean(value)) => BoltType::Boolean(value),
+            (Self::List, BoltType::List(value)) => BoltType::List(value),
+            // Empty Lua tables are converted to lists
+            (Self::Map, BoltType::List(value)) if value.is_empty() => BoltType::Map(BoltMap::new()),
+            (Self::Map, BoltType::Map(value)) => BoltType::Map(value),
+            (prop_type, value) => {
+                return Err(anyhow!(
+                    "Property '{}' must be {}, got {}",
+                    property,
+                    prop_type.name(),
+                    bolt_type_name(&value)
+                ));
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+fn bolt_type_name(value: &BoltType) -> &'static str {
+    match value {
+        BoltType::String(_) => "string",
+        BoltType::Integer(_) => "integer",
+        BoltType::Float(_) => "number",
+        BoltType::Boolean(_) => "boolean",
+        BoltType::List(_) => "list",
+        BoltType::Map(_) => "map",
+        BoltType::Null(_) => "nil",
+        _ => "another type",
+    }
+}
+
+pub struct ModelDef {
+    pub label: String,
+    pub key: String,
+    /// Declared properties, any property is accepted if empty
+    pub props: BTreeMap<String, PropType>,
+    /// Outgoing relationship types and the label of the model they point to
+    pub relations: BTreeMap<String, String>,
+}
+
+impl ModelDef {
+    fn prop_type(&self, property: &str) -> PropType {
+        self.props.get(property).copied().unwrap_or(PropType::Any)
+    }
+
+    fn node(&self, variable: &str, param: &str) -> String {
+        format!(
+            "({}:{} {{{}: ${}}})",
+            variable,
+            escape_identifier(&self.label),
+            escape_identifier(&self.key),
+            param
+        )
+    }
+
+    /// Label of the nodes a relation points to.
+    fn relation_label(&self, relation: &str) -> anyhow::Result<&str> {
+        self.relations
+            .get(relation)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("Model '{}' has no relation '{}'", self.label, relation))
+    }
+
+    fn find_query(&self) -> String {
+        format!("MATCH {} RETURN n", self.node("n", "key"))
+    }
+
+    fn save_query(&self) -> String {
+        format!("MERGE {} SET n += $props RETURN n", self.node("n", "key"))
+    }
+
+    fn delete_query(&self) -> String {
+        format!("MATCH {} DETACH DELETE n", self.node("n", "key"))
+    }
+
+    fn related_query(&self, relation: &str, label: &str) -> String {
+        format!(
+            "MATCH {}-[:{}]->(m:{}) RETURN m",
+            self.node("n", "key"),
+            escape_identifier(relation),
+            escape_identifier(label)
+        )
+    }
+
+    fn link_query(&self, relation: &str, other: &ModelDef) -> String {
+        format!(
+            "MATCH {}, {} MERGE (n)-[r:{}]->(m) SET r += $props",
+            self.node("n", "key"),
+            other.node("m", "other"),
+            escape_identifier(relation)
+        )
+    }
+
+    fn unlink_query(&self, relation: &str, other: &ModelDef) -> String {
+        format!(
+            "MATCH {}-[r:{}]->{} DELETE r",
+            self.node("n", "key"),
+            escape_identifier(relation),
+            other.node("m", "other")
+        )
+    }
+
+    /// Model of the nodes a relation points to, needed to match them by key.
+    fn related(&self, relation: &str) -> anyhow::Result<Arc<ModelDef>> {
+        let label = self.relation_label(relation)?;
+        get_model(label).ok_or_else(|| {
+            anyhow!(
+                "Relation '{}' of '{}' points to '{}', which is not a model",
+                relation,
+                self.label,
+                label
+            )
+        })
+    }
+}
+
+lazy_static! {
+    static ref MODELS: RwLock<HashMap<String, Arc<ModelDef>>> = RwLock::new(HashMap::new());
+}
+
+pub fn get_model(label: &str) -> Option<Arc<ModelDef>> {
+    MODELS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(label)
+        .cloned()
+}
+
+pub struct LuaNeoModel(pub Arc<ModelDef>);
+
+register_lua_rstruct!(LuaNeoModel, c"Neo4jModel", &[
+    (c"Find", find),
+    (c"Save", save),
+    (c"Delete", delete),
+    (c"Related", related),
+    (c"Link", link),
+    (c"Unlink", unlink),
+]);
+
+/// Reads a table of string keys to string values, such as `props`.
+fn read_string_map(
+    l: lua::State,
+    index: i32,
+    field: &str,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut map = BTreeMap::new();
+    unsafe {
+        l.push_nil();
+        while l.next(index) != 0 {
+            if l.lua_type(-2) != LUA_TSTRING || l.lua_type(-1) != LUA_TSTRING {
+                let (key_type, value_type) = (l.lua_type(-2), l.lua_type(-1));
+                l.pop_n(2);
+                return Err(anyhow!(
+                    "'{}' must map strings to strings, got {} = {}",
+                    field,
+                    lua_type_name(key_type),
+                    lua_type_name(value_type)
+                ));
+            }
+            map.insert(l.get_string_unchecked(-2), l.get_string_unchecked(-1));
+            l.pop();
+        }
+    }
+
+    Ok(map)
+}
+
+fn read_optional_map(
+    l: lua::State,
+    index: i32,
+    field: &std::ffi::CStr,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    l.get_field(index, field);
+    let map = if l.is_table(-1) {
+        read_string_map(l, l.get_top(), &field.to_string_lossy())
+    } else if l.is_none_or_nil(-1) {
+        Ok(BTreeMap::new())
+    } else {
+        Err(anyhow!("'{}' must be a table", field.to_string_lossy()))
+    };
+    l.pop_n(1);
+
+    map
+}
+
+#[lua_function]
+pub fn new_model(l: lua::State) -> anyhow::Result<i32> {
+    let label = l.check_string(1)?;
+    // Model queries are built from names checked here and are not in the
+    // allowlist, so in strict mode only startup code can define them
+    check_startup(l, "Model")?;
+
+    if !l.is_table(2) {
+        return Err(Error::msg(
+            "Model expects a table of options as its second argument",
+        ));
+    }
+
+    l.get_field(2, c"key");
+    let key = l.is_string(-1).then(|| l.get_string_unchecked(-1));
+    l.pop_n(1);
+    let key = key.ok_or_else(|| anyhow!("Model '{}' needs a 'key' property", label))?;
+
+    let mut props = BTreeMap::new();
+    for (property, type_name) in read_optional_map(l, 2, c"props")? {
+        let prop_type = PropType::parse(&type_name).ok_or_else(|| {
+            anyhow!(
+                "Unknown type '{}' for property '{}' (expected string, number, integer, boolean, \
+                 list, map or any)",
+                type_name,
+                property
+            )
+        })?;
+        props.insert(property, prop_type);
+    }
+    if !props.is_empty() && !props.contains_key(&key) {
+        return Err(anyhow!(
+            "Model '{}' declares properties but not its key '{}'",
+            label,
+            key
+        ));
+    }
+
+    let relations = read_optional_map(l, 2, c"relations")?;
+
+    // Names end up in query text, so check them before any query is built
+    let names = [&label, &key]
+        .into_iter()
+        .chain(props.keys())
+        .chain(relations.keys())
+        .chain(relations.values());
+    for name in names {
+        validate_identifier(name).map_err(|err| anyhow!("Model '{}': {}", label, err))?;
+    }
+
+    let model = Arc::new(ModelDef {
+        label: label.clone(),
+        key,
+        props,
+        relations,
+    });
+
+    // Defining a label again replaces it, so models can be reloaded
+    MODELS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(label, model.clone());
+
+    l.push_struct::<LuaNeoModel>(LuaNeoModel(model));
+
+    Ok(1)
+}
+
+/// Converts the key value at `index` to the declared key type.
+fn read_key(l: lua::State, model: &ModelDef, index: i32) -> anyhow::Result<BoltType> {
+    if l.is_none_or_nil(index) {
+        return Err(anyhow!("Expected a value for the key '{}'", model.key));
+    }
+    let value = lua_value_to_bolttype(l, index, &model.key)?;
+    model.prop_type(&model.key).coerce(value, &model.key)
+}
+
+fn push_query(l: lua::State, text: String, params: Vec<(&str, BoltType)>) -> anyhow::Result<()> {
+    let mut query = NeoQuery::new(text);
+    for (name, value) in params {
+        query.params.put(BoltString::from(name), value);
+    }
+
+    l.push_struct::<LuaNeoQuery>(LuaNeoQuery::new(query));
+    Ok(())
+}
+
+#[lua_function]
+pub fn find(l: lua::State) -> anyhow::Result<i32> {
+    let model = l.get_struct::<LuaNeoModel>(1)?.0.clone();
+    let key = read_key(l, &model, 2)?;
+
+    push_query(l, model.find_query(), vec![("key", key)])?;
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn save(l: lua::State) -> anyhow::Result<i32> {
+    let model = l.get_struct::<LuaNeoModel>(1)?.0.clone();
+
+    if !l.is_table(2) {
+        return Err(Error::msg("Save expects a table of properties"));
+    }
+    let values = lua_table_to_boltmap(l, 2, "")?;
+
+    let mut key = None;
+    let mut props = BoltMap::new();
+    for (property, value) in values.value {
+        if !model.props.is_empty() && !model.props.contains_key(&property.value) {
+            return Err(anyhow!(
+                "Model '{}' has no property '{}'",
+                model.label,
+                property.value
+            ));
+        }
+
+        let value = model
+            .prop_type(&property.value)
+            .coerce(value, &property.value)?;
+        if property.value == model.key {
+            key = Some(value);
+        } else {
+            props.put(property, value);
+        }
+    }
+    let key = key.ok_or_else(|| anyhow!("Save needs a value for the key '{}'", model.key))?;
+
+    push_query(
+        l,
+        model.save_query(),
+        vec![("key", key), ("props", BoltType::Map(props))],
+    )?;
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn delete(l: lua::State) -> anyhow::Result<i32> {
+    let model = l.get_struct::<LuaNeoModel>(1)?.0.clone();
+    let key = read_key(l, &model, 2)?;
+
+    push_query(l, model.delete_query(), vec![("key", key)])?;
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn related(l: lua::State) -> anyhow::Result<i32> {
+    let model = l.get_struct::<LuaNeoModel>(1)?.0.clone();
+    let key = read_key(l, &model, 2)?;
+    let relation = l.check_string(3)?;
+    // Only the label is needed, so the target doesn't have to be a model
+    let label = model.relation_label(&relation)?;
+
+    push_query(l, model.related_query(&relation, label), vec![("key", key)])?;
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn link(l: lua::State) -> anyhow::Result<i32> {
+    let model = l.get_struct::<LuaNeoModel>(1)?.0.clone();
+    let key = read_key(l, &model, 2)?;
+    let relation = l.check_string(3)?;
+    let other = model.related(&relation)?;
+    let other_key = read_key(l, &other, 4)?;
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(5) && !l.is_table(5) {
+        return Err(Error::msg("Relationship properties must be a table"));
+    }
+    let props = if l.is_table(5) {
+        lua_table_to_boltmap(l, 5, "")?
+    } else {
+        BoltMap::new()
+    };
+
+    push_query(
+        l,
+        model.link_query(&relation, &other),
+        vec![
+            ("key", key),
+            ("other", other_key),
+            ("props", BoltType::Map(props)),
+        ],
+    )?;
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn unlink(l: lua::State) -> anyhow::Result<i32> {
+    let model = l.get_struct::<LuaNeoModel>(1)?.0.clone();
+    let key = read_key(l, &model, 2)?;
+    let relation = l.check_string(3)?;
+    let other = model.related(&relation)?;
+    let other_key = read_key(l, &other, 4)?;
+
+    push_query(
+        l,
+        model.unlink_query(&relation, &other),
+        vec![("key", key), ("other", other_key)],
+    )?;
+
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use neo4rs::{BoltBoolean, BoltInteger, BoltList};
+
+    use super::*;
+
+    fn player() -> ModelDef {
+        ModelDef {
+            label: "Player".to_string(),
+            key: "steam_id".to_string(),
+            props: BTreeMap::new(),
+            relations: BTreeMap::from([("OWNS".to_string(), "Item".to_string())]),
+        }
+    }
+
+    fn item() -> ModelDef {
+        ModelDef {
+            label: "Item".to_string(),
+            key: "id".to_string(),
+            props: BTreeMap::new(),
+            relations: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn prop_types_parse_aliases() {
+        assert!(PropType::parse("INT") == Some(PropType::Integer));
+        assert!(PropType::parse("table") == Some(PropType::Map));
+        assert!(PropType::parse("float") == Some(PropType::Number));
+        assert!(PropType::parse("date").is_none());
+    }
+
+    #[test]
+    fn values_are_coerced_to_the_declared_type() {
+        let integer = BoltType::Integer(BoltInteger::new(76561198000000000));
+        assert_eq!(
+            PropType::String.coerce(integer.clone(), "id").unwrap(),
+            BoltType::String(BoltString::from("76561198000000000"))
+        );
+        assert_eq!(
+            PropType::Number
+                .coerce(BoltType::Integer(BoltInteger::new(2)), "x")
+                .unwrap(),
+            BoltType::Float(BoltFloat::new(2.0))
+        );
+        assert_eq!(
+            PropType::Map
+                .coerce(BoltType::List(BoltList::new()), "m")
+                .unwrap(),
+            BoltType::Map(BoltMap::new())
+        );
+        assert_eq!(PropType::Any.coerce(integer.clone(), "x").unwrap(), integer);
+    }
+
+    #[test]
+    fn mismatched_values_are_refused() {
+        let err = PropType::Integer
+            .coerce(BoltType::Boolean(BoltBoolean::new(true)), "level")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Property 'level' must be integer, got boolean");
+        assert!(
+            PropType::Integer
+                .coerce(BoltType::Float(BoltFloat::new(1.5)), "level")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn generated_queries_escape_names_and_use_params() {
+        let (player, item) = (player(), item());
+        assert_eq!(
+            player.find_query(),
+            "MATCH (n:`Player` {`steam_id`: $key}) RETURN n"
+        );
+        assert_eq!(
+            player.save_query(),
+            "MERGE (n:`Player` {`steam_id`: $key}) SET n += $props RETURN n"
+        );
+        assert_eq!(
+            player.delete_query(),
+            "MATCH (n:`Player` {`steam_id`: $key}) DETACH DELETE n"
+        );
+        assert_eq!(
+            player.related_query("OWNS", "Item"),
+            "MATCH (n:`Player` {`steam_id`: $key})-[:`OWNS`]->(m:`Item`) RETURN m"
+        );
+        assert_eq!(
+            player.link_query("OWNS", &item),
+            "MATCH (n:`Player` {`steam_id`: $key}), (m:`Item` {`id`: $other}) MERGE \
+             (n)-[r:`OWNS`]->(m) SET r += $props"
+        );
+        assert_eq!(
+            player.unlink_query("OWNS", &item),
+            "MATCH (n:`Player` {`steam_id`: $key})-[r:`OWNS`]->(m:`Item` {`id`: $other}) DELETE r"
+        );
+    }
+
+    #[test]
+    fn relations_resolve_to_labels() {
+        assert_eq!(player().relation_label("OWNS").unwrap(), "Item");
+        assert!(player().relation_label("KNOWS").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+
+use anyhow::{Error, anyhow};
+use gmod::{lua, lua_function};
+use lazy_static::lazy_static;
+
+use crate::api::allowlist::{self, check_allowed};
+use crate::api::query::{LuaNeoQuery, NeoQuery, ParamValidation, params_from_table};
+use crate::cypher::parameter_names;
+use crate::files::{CypherFile, read_cypher_files};
+use crate::runtime;
+
+pub struct NamedQuery {
+    pub text: String,
+    pub source: String,
+}
+
+lazy_static! {
+    static ref NAMED_QUERIES: RwLock<HashMap<String, NamedQuery>> = RwLock::new(HashMap::new());
+}
+
+pub fn get_named_query(name: &str) -> Option<String> {
+    NAMED_QUERIES
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(name)
+        .map(|named| named.text.clone())
+}
+
+fn header_value<'a>(line: &'a str, header: &str) -> Option<&'a str> {
+    let comment = line.trim().strip_prefix("//")?.trim_start();
+    let value = comment
+        .strip_prefix(header)?
+        .trim_start()
+        .strip_prefix(':')?;
+    Some(value.trim())
+}
+
+struct ParsedQuery {
+    name: String,
+    declared: Option<BTreeSet<String>>,
+    text: String,
+}
+
+fn parse_file(file: &CypherFile) -> anyhow::Result<Vec<ParsedQuery>> {
+    let mut queries: Vec<ParsedQuery> = Vec::new();
+
+    for line in file.contents.lines() {
+        if let Some(name) = header_value(line, "name") {
+            if name.is_empty() {
+                return Err(anyhow!("{}: empty query name", file.name));
+            }
+            queries.push(ParsedQuery {
+                name: name.to_string(),
+                declared: None,
+                text: String::new(),
+            });
+            continue;
+        }
+
+        let Some(current) = queries.last_mut() else {
+            if line.trim().is_empty() || line.trim().starts_with("//") {
+                continue;
+            }
+            return Err(anyhow!(
+                "{}: query text found before a '// name: ...' header",
+                file.name
+            ));
+        };
+
+        if let Some(params) = header_value(line, "params") {
+            let declared = params
+                .split(',')
+                .map(|param| param.trim().trim_start_matches('$').to_string())
+                .filter(|param| !param.is_empty())
+                .collect();
+            current.declared = Some(declared);
+            continue;
+        }
+
+        current.text.push_str(line);
+        current.text.push('\n');
+    }
+
+    for query in queries.iter_mut() {
+        query.text = query
+            .text
+            .trim()
+            .trim_end_matches(';')
+            .trim_end()
+            .to_string();
+        if query.text.is_empty() {
+            return Err(anyhow!("{}: query '{}' has no text", file.name, query.name));
+        }
+    }
+
+    Ok(queries)
+}
+
+fn validate_params(file: &CypherFile, query: &ParsedQuery) -> anyhow::Result<()> {
+    // Only queries with a '// params:' header are checked
+    let Some(declared) = &query.declared else {
+        return Ok(());
+    };
+
+    let used = parameter_names(&query.text);
+    let undeclared: Vec<String> = used
+        .difference(declared)
+        .map(|p| format!("${}", p))
+        .collect();
+    let unused: Vec<String> = declared
+        .difference(&used)
+        .map(|p| format!("${}", p))
+        .collect();
+
+    if undeclared.is_empty() && unused.is_empty() {
+        return Ok(());
+    }
+
+    let mut problems = Vec::new();
+    if !undeclared.is_empty() {
+        problems.push(format!("used but not declared: {}", undeclared.join(", ")));
+    }
+    if !unused.is_empty() {
+        problems.push(format!("declared but not used: {}", unused.join(", ")));
+    }
+
+    Err(anyhow!(
+        "{}: query '{}' parameters do not match ({})",
+        file.name,
+        query.name,
+        problems.join("; ")
+    ))
+}
+
+#[lua_function]
+pub fn load_queries(l: lua::State) -> anyhow::Result<i32> {
+    let dir = l.check_string(1)?;
+    // Loaded queries are allowed in strict mode, so this is startup only too
+    allowlist::check_startup(l, "LoadQueries")?;
+
+    // Parse and validate everything before touching the registry, so a bad
+    // file does not leave it half loaded
+    let mut loaded: HashMap<String, NamedQuery> = HashMap::new();
+    for file in read_cypher_files(&dir)? {
+        for query in parse_file(&file)? {
+            validate_params(&file, &query)?;
+
+            if let Some(existing) = loaded.get(&query.name) {
+                return Err(anyhow!(
+                    "{}: query '{}' is already defined in {}",
+                    file.name,
+                    query.name,
+                    existing.source
+                ));
+            }
+
+            loaded.insert(
+                query.name,
+                NamedQuery {
+                    text: query.text,
+                    source: file.path.display().to_string(),
+                },
+            );
+        }
+    }
+
+    for named in loaded.values() {
+        allowlist::register(l, "LoadQueries", &named.text)?;
+    }
+
+    let count = loaded.len();
+    NAMED_QUERIES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .extend(loaded);
+
+    l.push_number(count as f64);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn named_query(l: lua::State) -> anyhow::Result<i32> {
+    let name = l.check_string(1)?;
+
+    let text = get_named_query(&name)
+        .ok_or_else(|| anyhow!("No query named '{}' has been loaded", name))?;
+    check_allowed(&text)?;
+
+    let mut query = NeoQuery::new(text);
+
+    // Catch bad parameter sets
+    if !l.is_none_or_nil(2) && !l.is_table(2) {
+        return Err(Error::msg(
+            "Parameter argument of Named must be a table of parameters",
+        ));
+    }
+
+    if l.is_table(2) {
+        query.params = params_from_table(l, 2)?;
+
+        if runtime::param_validation() == ParamValidation::Error {
+            query.validate(ParamValidation::Error)?;
+        }
+    }
+
+    l.push_struct::<LuaNeoQuery>(LuaNeoQuery::new(query));
+
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn file(contents: &str) -> CypherFile {
+        CypherFile {
+            name: "users.cypher".to_string(),
+            path: PathBuf::from("users.cypher"),
+            contents: contents.to_string(),
+        }
+    }
+
+    #[test]
+    fn header_values_need_the_comment_and_colon() {
+        assert_eq!(
+            header_value("  //  name : find_user ", "name"),
+            Some("find_user")
+        );
+        assert_eq!(header_value("// params:", "params"), Some(""));
+        assert_eq!(header_value("name: find_user", "name"), None);
+        assert_eq!(header_value("// name find_user", "name"), None);
+        assert_eq!(header_value("// params: $id", "name"), None);
+    }
+
+    #[test]
+    fn files_split_into_named_queries() {
+        let queries = parse_file(&file(
+            "// Users\n\n// name: find_user\n// params: $id, limit\nMATCH (u {id: $id})\nRETURN u \
+             LIMIT $limit;\n\n// name: count_users\nMATCH (u) RETURN count(u)\n",
+        ))
+        .unwrap();
+
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].name, "find_user");
+        assert_eq!(
+            queries[0].text,
+            "MATCH (u {id: $id})\nRETURN u LIMIT $limit"
+        );
+        assert_eq!(
+            queries[0].declared,
+            Some(BTreeSet::from(["id".to_string(), "limit".to_string()]))
+        );
+        assert_eq!(queries[1].name, "count_users");
+        assert_eq!(queries[1].declared, None);
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        assert!(parse_file(&file("MATCH (n) RETURN n")).is_err());
+        assert!(parse_file(&file("// name:\nMATCH (n) RETURN n")).is_err());
+        assert!(parse_file(&file("// name: empty\n;\n")).is_err());
+    }
+
+    #[test]
+    fn declared_params_must_match_the_text() {
+        let mismatched = file("// name: q\n// params: id, unused\nMATCH (n {id: $id, x: $x})");
+        let query = parse_file(&mismatched).unwrap().remove(0);
+        let err = validate_params(&mismatched, &query)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("used but not declared: $x"), "{}", err);
+        assert!(err.contains("declared but not used: $unused"), "{}", err);
+
+        let undeclared = file("// name: q\nMATCH (n {id: $id})");
+        let query = parse_file(&undeclared).unwrap().remove(0);
+        assert!(validate_params(&undeclared, &query).is_ok());
+    }
+}
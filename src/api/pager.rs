@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::anyhow;
+use gmod::rstruct::RStruct;
+use gmod::{lua, lua_function, register_lua_rstruct};
+use neo4rs::{BoltInteger, BoltMap, BoltNull, BoltString, BoltType, Graph};
+use tokio::sync::Mutex;
+
+use crate::api::graph::{GraphState, handle_graph_execution};
+use crate::api::promise::LuaNeoPromise;
+use crate::api::query::NeoQuery;
+use crate::api::result::{ResultSet, dispatch_callback};
+use crate::cypher::{parameter_names, return_columns};
+use crate::runtime;
+
+/// Runs a keyset paginated query one page at a time. The query text is used
+/// as is and has to filter on `$after` and limit to `$limit` itself.
+pub struct LuaNeoPager {
+    /// Looked up for every page, so the pager stops once the graph is closed
+    graph: Arc<GraphState>,
+    query: NeoQuery,
+    order_key: String,
+    page_size: usize,
+    /// Order key value of the last row seen, null before the first page
+    after: Arc<Mutex<BoltType>>,
+    exhausted: Arc<AtomicBool>,
+}
+
+impl LuaNeoPager {
+    pub fn new(
+        graph: Arc<GraphState>,
+        query: NeoQuery,
+        order_key: String,
+        page_size: usize,
+    ) -> anyhow::Result<Self> {
+        let used = parameter_names(&query.text);
+        for param in ["after", "limit"] {
+            if !used.contains(param) {
+                return Err(anyhow!(
+                    "Paginated queries must use ${}, e.g. WHERE $after IS NULL OR x > $after \
+                     ORDER BY x LIMIT $limit",
+                    param
+                ));
+            }
+        }
+
+        let pager = Self {
+            graph,
+            query,
+            order_key,
+            page_size,
+            after: Arc::new(Mutex::new(BoltType::Null(BoltNull))),
+            exhausted: Arc::new(AtomicBool::new(false)),
+        };
+        pager
+            .page_query(BoltType::Null(BoltNull))
+            .validate(runtime::param_validation())?;
+
+        Ok(pager)
+    }
+
+    fn page_query(&self, after: BoltType) -> NeoQuery {
+        let mut query = self.query.clone();
+        query.params.put(BoltString::from("after"), after);
+        query.params.put(
+            BoltString::from("limit"),
+            BoltType::Integer(BoltInteger::from(self.page_size as i64)),
+        );
+        query
+    }
+}
+
+register_lua_rstruct!(LuaNeoPager, c"Neo4jPager", &[
+    (c"NextPage", next_page),
+    (c"HasMore", has_more),
+]);
+
+async fn handle_next_page(
+    graph: Graph,
+    query: NeoQuery,
+    order_key: String,
+    page_size: usize,
+    after: Arc<Mutex<BoltType>>,
+    exhausted: Arc<AtomicBool>,
+) -> anyhow::Result<Vec<BoltMap>> {
+    // Pages are read one after another, each starting where the last ended
+    let mut after = after.lock().await;
+    if exhausted.load(Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    let mut query = query;
+    query.params.put(BoltString::from("after"), after.clone());
+    let rows = handle_graph_execution(graph, query.to_query()).await?;
+
+    if let Some(last) = rows.last() {
+        let key = last.value.get(order_key.as_str()).ok_or_else(|| {
+            anyhow!(
+                "Paginated query did not return the order key '{}'",
+                order_key
+            )
+        })?;
+        *after = key.clone();
+    }
+    if rows.len() < page_size {
+        exhausted.store(true, Ordering::SeqCst);
+    }
+
+    Ok(rows)
+}
+
+#[lua_function]
+pub fn next_page(l: lua::State) -> anyhow::Result<i32> {
+    let pager = l.get_struct::<LuaNeoPager>(1)?;
+
+    // $after is filled in once earlier pages are done
+    let page = handle_next_page(
+        pager.graph.graph()?,
+        pager.page_query(BoltType::Null(BoltNull)),
+        pager.order_key.clone(),
+        pager.page_size,
+        pager.after.clone(),
+        pager.exhausted.clone(),
+    );
+    let page = pager.graph.track(page);
+
+    // Without a callback, hand back a promise instead
+    if l.is_none_or_nil(2) {
+        let promise = LuaNeoPromise::spawn(page);
+        l.push_struct::<LuaNeoPromise>(promise);
+        return Ok(1);
+    }
+    let callback = l.check_function(2)?;
+    let columns = return_columns(&pager.query.text);
+
+    runtime::run_async(async move {
+        let results = page.await.map(|rows| ResultSet::new(rows, columns));
+
+        dispatch_callback(callback, results);
+    });
+
+    Ok(0)
+}
+
+#[lua_function]
+pub fn has_more(l: lua::State) -> anyhow::Result<i32> {
+    let pager = l.get_struct::<LuaNeoPager>(1)?;
+
+    l.push_boolean(!pager.exhausted.load(Ordering::SeqCst));
+
+    Ok(1)
+}
@@ -0,0 +1,478 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::anyhow;
+use gmod::rstruct::RStruct;
+use gmod::{
+    LUA_TTHREAD, LUA_TUSERDATA, LuaReference, lua, lua_function, register_lua_rstruct,
+    wait_lua_tick,
+};
+use neo4rs::BoltMap;
+
+use crate::api::result::push_rows;
+use crate::runtime;
+
+/// A Lua value returned by a promise handler, released on the next tick once
+/// no promise refers to it anymore.
+pub struct LuaValue(LuaReference);
+
+impl Drop for LuaValue {
+    fn drop(&mut self) {
+        let reference = self.0;
+        wait_lua_tick(move |l| l.dereference(reference));
+    }
+}
+
+pub enum PromiseValue {
+    Rows(Vec<BoltMap>),
+    Lua(LuaValue),
+    List(Vec<Arc<Outcome>>),
+}
+
+pub type Outcome = Result<PromiseValue, String>;
+
+type Waiter = Box<dyn FnOnce(lua::State, &Arc<Outcome>) + Send>;
+
+enum PromiseState {
+    Pending(Vec<Waiter>),
+    Settled {
+        outcome: Arc<Outcome>,
+        /// Whether anything waited for the outcome, used to report
+        /// rejections nobody handles
+        observed: bool,
+    },
+}
+
+type SharedState = Arc<Mutex<PromiseState>>;
+
+/// Result of work running on the worker runtime. Waiters (coroutines,
+/// handlers and combinators) are run from the Lua tick once it settles.
+pub struct LuaNeoPromise(SharedState);
+
+register_lua_rstruct!(LuaNeoPromise, c"Neo4jPromise", &[
+    (c"Await", await_promise),
+    (c"Then", then),
+    (c"Catch", catch),
+    (c"Finally", finally),
+]);
+
+impl LuaNeoPromise {
+    pub fn pending() -> Self {
+        Self(Arc::new(Mutex::new(PromiseState::Pending(Vec::new()))))
+    }
+
+    /// Runs the future on the module runtime and settles the promise with
+    /// its output.
+    pub fn spawn<F>(fut: F) -> Self
+    where
+        F: Future<Output = anyhow::Result<Vec<BoltMap>>> + Send + 'static,
+    {
+        let promise = Self::pending();
+        let handle = runtime::run_async(fut);
+
+        let state = promise.0.clone();
+        runtime::run_async(async move {
+            let outcome = match handle.await {
+                Ok(result) => result
+                    .map(PromiseValue::Rows)
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(format!("Query task failed: {}", err)),
+            };
+
+            wait_lua_tick(move |l| settle(l, &state, Arc::new(outcome)));
+        });
+
+        promise
+    }
+}
+
+fn lock_state(state: &Mutex<PromiseState>) -> MutexGuard<'_, PromiseState> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn settle(l: lua::State, state: &SharedState, outcome: Arc<Outcome>) {
+    let waiters = {
+        let mut guard = lock_state(state);
+        let PromiseState::Pending(waiters) = &mut *guard else {
+            return;
+        };
+        let waiters = std::mem::take(waiters);
+        *guard = PromiseState::Settled {
+            outcome: outcome.clone(),
+            observed: !waiters.is_empty(),
+        };
+        waiters
+    };
+
+    if waiters.is_empty() && outcome.is_err() {
+        report_unhandled(state.clone());
+    }
+
+    // The lock is released first, so waiters may chain onto this promise
+    for waiter in waiters {
+        waiter(l, &outcome);
+    }
+}
+
+/// Logs a rejection if no handler was added by the next tick, the same way
+/// errors in awaiting coroutines are logged.
+fn report_unhandled(state: SharedState) {
+    wait_lua_tick(move |_| {
+        if let PromiseState::Settled {
+            outcome,
+            observed: false,
+        } = &*lock_state(&state)
+            && let Err(err) = &**outcome
+        {
+            eprintln!("[neo4j] Unhandled promise rejection: {}", err);
+        }
+    });
+}
+
+/// Runs the waiter once the promise settles. Waiters added to a settled
+/// promise run on the next tick, so handlers never run before `Then` returns.
+fn when_settled(state: &SharedState, waiter: Waiter) {
+    let mut guard = lock_state(state);
+    match &mut *guard {
+        PromiseState::Pending(waiters) => waiters.push(waiter),
+        PromiseState::Settled { outcome, observed } => {
+            *observed = true;
+            let outcome = outcome.clone();
+            drop(guard);
+            wait_lua_tick(move |l| waiter(l, &outcome));
+        }
+    }
+}
+
+fn push_value(l: lua::State, value: &PromiseValue) -> anyhow::Result<()> {
+    match value {
+        PromiseValue::Rows(rows) => push_rows(l, rows)?,
+        PromiseValue::Lua(value) => l.from_reference(value.0),
+        PromiseValue::List(items) => {
+            l.new_table();
+            for (i, item) in items.iter().enumerate() {
+                match &**item {
+                    Ok(value) => push_value(l, value)?,
+                    Err(_) => l.push_nil(),
+                }
+                l.raw_seti(-2, i as i32 + 1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Pushes `value, err` for an outcome.
+fn push_outcome(l: lua::State, outcome: &Outcome) {
+    match outcome {
+        Ok(value) => {
+            let top = l.get_top();
+            if let Err(err) = push_value(l, value) {
+                // Drop whatever the failed conversion left behind
+                l.pop_n(l.get_top() - top);
+                l.push_nil();
+                l.push_string(&format!("Could not convert bolt map to Lua table: {}", err));
+            } else {
+                l.push_nil();
+            }
+        }
+        Err(err) => {
+            l.push_nil();
+            l.push_string(err);
+        }
+    }
+}
+
+fn resume_coroutine(l: lua::State, thread: LuaReference, outcome: &Outcome) {
+    l.get_global(c"coroutine");
+    l.get_field(-1, c"resume");
+    let success = l.pcall_ignore(|| {
+        l.from_reference(thread);
+        push_outcome(l, outcome);
+        2
+    });
+
+    if success {
+        // coroutine.resume returns false and the message if the coroutine errored
+        let resumed = l.check_boolean(-2).unwrap_or(true);
+        if !resumed {
+            let message = l.check_string(-1).unwrap_or_default();
+            eprintln!("[neo4j] Error in awaiting coroutine: {}", message);
+        }
+        l.pop_n(2);
+    }
+    l.pop();
+
+    l.dereference(thread);
+}
+
+/// Calls a handler with the outcome's value (or error message) and returns a
+/// reference to whatever it returned. Errors raised by the handler come back
+/// with their message and traceback.
+fn call_handler(
+    l: lua::State,
+    handler: LuaReference,
+    args: Option<&Outcome>,
+) -> Result<LuaReference, String> {
+    // xpcall(handler, debug.traceback, ...) keeps the error, which
+    // pcall_ignore would only print
+    l.get_global(c"debug");
+    l.get_field(-1, c"traceback");
+    l.get_global(c"xpcall");
+    let success = l.pcall_ignore(|| {
+        l.from_reference(handler);
+        l.push_value(-3);
+        match args {
+            Some(Ok(value)) => {
+                let top = l.get_top();
+                if push_value(l, value).is_err() {
+                    // Drop whatever the failed conversion left behind
+                    l.pop_n(l.get_top() - top);
+                    l.push_nil();
+                }
+            }
+            Some(Err(err)) => l.push_string(err),
+            None => {}
+        }
+        2
+    });
+    l.dereference(handler);
+
+    if !success {
+        l.pop_n(2);
+        return Err("Could not call the promise handler".to_string());
+    }
+
+    // Stack: debug, traceback, ok, value or error
+    if !l.check_boolean(-2).unwrap_or(false) {
+        let message = l
+            .check_string(-1)
+            .unwrap_or_else(|_| "A promise handler raised an error".to_string());
+        l.pop_n(4);
+        return Err(message);
+    }
+
+    let returned = l.reference();
+    l.pop_n(3);
+    Ok(returned)
+}
+
+/// Settles `next` with what a handler returned, following returned promises.
+fn adopt(l: lua::State, next: &SharedState, returned: LuaReference) {
+    l.from_reference(returned);
+    let inner = if l.lua_type(-1) == LUA_TUSERDATA {
+        l.get_struct::<LuaNeoPromise>(l.get_top())
+            .ok()
+            .map(|promise| promise.0.clone())
+    } else {
+        None
+    };
+    l.pop();
+
+    match inner {
+        Some(inner) => {
+            l.dereference(returned);
+            let next = next.clone();
+            when_settled(
+                &inner,
+                Box::new(move |l, outcome| settle(l, &next, outcome.clone())),
+            );
+        }
+        None => settle(l, next, Arc::new(Ok(PromiseValue::Lua(LuaValue(returned))))),
+    }
+}
+
+fn optional_function(l: lua::State, index: i32) -> anyhow::Result<Option<LuaReference>> {
+    if l.is_none_or_nil(index) {
+        return Ok(None);
+    }
+    Ok(Some(l.check_function(index)?))
+}
+
+fn chain(
+    l: lua::State,
+    on_ok: Option<LuaReference>,
+    on_err: Option<LuaReference>,
+) -> anyhow::Result<i32> {
+    let promise = l.get_struct::<LuaNeoPromise>(1)?;
+
+    let next = LuaNeoPromise::pending();
+    let next_state = next.0.clone();
+    when_settled(
+        &promise.0,
+        Box::new(move |l, outcome| {
+            let (handler, unused) = match &**outcome {
+                Ok(_) => (on_ok, on_err),
+                Err(_) => (on_err, on_ok),
+            };
+            if let Some(unused) = unused {
+                l.dereference(unused);
+            }
+
+            match handler {
+                Some(handler) => match call_handler(l, handler, Some(outcome)) {
+                    Ok(returned) => adopt(l, &next_state, returned),
+                    Err(err) => settle(l, &next_state, Arc::new(Err(err))),
+                },
+                // Nothing handles this outcome, so pass it along
+                None => settle(l, &next_state, outcome.clone()),
+            }
+        }),
+    );
+
+    l.push_struct::<LuaNeoPromise>(next);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn then(l: lua::State) -> anyhow::Result<i32> {
+    let on_ok = optional_function(l, 2)?;
+    let on_err = optional_function(l, 3)?;
+
+    chain(l, on_ok, on_err)
+}
+
+#[lua_function]
+pub fn catch(l: lua::State) -> anyhow::Result<i32> {
+    let on_err = l.check_function(2)?;
+
+    chain(l, None, Some(on_err))
+}
+
+#[lua_function]
+pub fn finally(l: lua::State) -> anyhow::Result<i32> {
+    let promise = l.get_struct::<LuaNeoPromise>(1)?;
+    let handler = l.check_function(2)?;
+
+    let next = LuaNeoPromise::pending();
+    let next_state = next.0.clone();
+    when_settled(
+        &promise.0,
+        Box::new(move |l, outcome| match call_handler(l, handler, None) {
+            Ok(returned) => {
+                l.dereference(returned);
+                settle(l, &next_state, outcome.clone());
+            }
+            Err(err) => settle(l, &next_state, Arc::new(Err(err))),
+        }),
+    );
+
+    l.push_struct::<LuaNeoPromise>(next);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn await_promise(l: lua::State) -> anyhow::Result<i32> {
+    let promise = l.get_struct::<LuaNeoPromise>(1)?;
+
+    if let PromiseState::Settled { outcome, observed } = &mut *lock_state(&promise.0) {
+        *observed = true;
+        push_outcome(l, outcome);
+        return Ok(2);
+    }
+
+    // Find out which coroutine we are running in
+    l.get_global(c"coroutine");
+    l.get_field(-1, c"running");
+    let success = l.pcall_ignore(|| 1);
+    if !success || l.lua_type(-1) != LUA_TTHREAD {
+        return Err(anyhow!(
+            "Await must be called from inside a coroutine; use a callback instead"
+        ));
+    }
+
+    let thread = l.reference();
+    when_settled(
+        &promise.0,
+        Box::new(move |l, outcome| resume_coroutine(l, thread, outcome)),
+    );
+
+    // The value and error are passed back through coroutine.resume
+    Ok(l.coroutine_yield(0))
+}
+
+fn read_promises(l: lua::State, index: i32) -> anyhow::Result<Vec<SharedState>> {
+    if !l.is_table(index) {
+        return Err(anyhow!("Expected a table of Neo4jPromise objects"));
+    }
+
+    let mut promises = Vec::new();
+    for i in 1..=l.len(index) {
+        l.raw_geti(index, i);
+        let promise = l
+            .get_struct::<LuaNeoPromise>(l.get_top())
+            .map(|promise| promise.0.clone())
+            .map_err(|_| anyhow!("Element {} is not a Neo4jPromise", i));
+        l.pop();
+        promises.push(promise?);
+    }
+
+    Ok(promises)
+}
+
+#[lua_function]
+pub fn all(l: lua::State) -> anyhow::Result<i32> {
+    let promises = read_promises(l, 1)?;
+
+    let combined = LuaNeoPromise::pending();
+    if promises.is_empty() {
+        let state = combined.0.clone();
+        wait_lua_tick(move |l| settle(l, &state, Arc::new(Ok(PromiseValue::List(Vec::new())))));
+    }
+
+    let results: Arc<Mutex<Vec<Option<Arc<Outcome>>>>> =
+        Arc::new(Mutex::new(vec![None; promises.len()]));
+    for (i, promise) in promises.iter().enumerate() {
+        let state = combined.0.clone();
+        let results = results.clone();
+        when_settled(
+            promise,
+            Box::new(move |l, outcome| {
+                // The first failure rejects the combined promise
+                if let Err(err) = &**outcome {
+                    settle(l, &state, Arc::new(Err(err.clone())));
+                    return;
+                }
+
+                let finished = {
+                    let mut results = results
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    results[i] = Some(outcome.clone());
+                    if results.iter().all(Option::is_some) {
+                        Some(results.drain(..).flatten().collect())
+                    } else {
+                        None
+                    }
+                };
+
+                if let Some(values) = finished {
+                    settle(l, &state, Arc::new(Ok(PromiseValue::List(values))));
+                }
+            }),
+        );
+    }
+
+    l.push_struct::<LuaNeoPromise>(combined);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn race(l: lua::State) -> anyhow::Result<i32> {
+    let promises = read_promises(l, 1)?;
+
+    // The first promise to settle wins, later ones are ignored by settle
+    let combined = LuaNeoPromise::pending();
+    for promise in promises.iter() {
+        let state = combined.0.clone();
+        when_settled(
+            promise,
+            Box::new(move |l, outcome| settle(l, &state, outcome.clone())),
+        );
+    }
+
+    l.push_struct::<LuaNeoPromise>(combined);
+    Ok(1)
+}
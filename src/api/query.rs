@@ -1,22 +1,168 @@
-use std::sync::Arc;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use gmod::rstruct::RStruct;
-use gmod::{lua, lua_function, register_lua_rstruct};
-use neo4rs::Query;
+use gmod::{LUA_TSTRING, lua, lua_function, register_lua_rstruct};
+use neo4rs::{BoltMap, BoltString, BoltType, Query};
 
-use crate::mapping::lua_table_to_boltmap;
+use crate::api::allowlist::check_allowed;
+use crate::api::ident::template_values;
+use crate::cypher::{parameter_names, render_template, return_columns};
+use crate::mapping::{boltmap_to_lua_table, lua_type_name, lua_value_to_bolttype};
+use crate::runtime;
 
-pub struct LuaNeoQuery(pub Arc<Query>);
+/// How missing or unused query parameters are reported, set through the
+/// `NEO4J_PARAM_VALIDATION` convar.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ParamValidation {
+    Off,
+    Warning,
+    Error,
+}
+
+impl ParamValidation {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" | "0" => Some(Self::Off),
+            "warning" | "warn" | "1" => Some(Self::Warning),
+            "error" | "2" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Query text and parameters, kept separately so they can be inspected and
+/// extended from Lua before a `neo4rs::Query` is built for execution.
+#[derive(Clone)]
+pub struct NeoQuery {
+    pub text: String,
+    pub params: BoltMap,
+}
+
+impl NeoQuery {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            params: BoltMap::new(),
+        }
+    }
+
+    pub fn to_query(&self) -> Query {
+        neo4rs::query(&self.text).params(self.params.value.clone())
+    }
+
+    /// Describes `$name` references without a value and values that the
+    /// text never references, or `None` if they match.
+    pub fn check_params(&self) -> Option<String> {
+        let used = parameter_names(&self.text);
+
+        let mut missing: Vec<String> = used
+            .iter()
+            .filter(|name| !self.params.value.contains_key(name.as_str()))
+            .map(|name| format!("${}", name))
+            .collect();
+        let mut unused: Vec<String> = self
+            .params
+            .value
+            .keys()
+            .filter(|key| !used.contains(&key.value))
+            .map(|key| key.value.clone())
+            .collect();
 
-register_lua_rstruct!(LuaNeoQuery, c"Neo4jQuery", &[]);
+        if missing.is_empty() && unused.is_empty() {
+            return None;
+        }
+        missing.sort();
+        unused.sort();
+
+        let mut problems = Vec::new();
+        if !missing.is_empty() {
+            problems.push(format!("missing parameters: {}", missing.join(", ")));
+        }
+        if !unused.is_empty() {
+            problems.push(format!("unused parameters: {}", unused.join(", ")));
+        }
+
+        Some(format!(
+            "Query [{}] has {}",
+            self.text.trim(),
+            problems.join("; ")
+        ))
+    }
+
+    /// Runs the parameter check according to the configured validation mode.
+    pub fn validate(&self, mode: ParamValidation) -> anyhow::Result<()> {
+        if mode == ParamValidation::Off {
+            return Ok(());
+        }
+
+        match self.check_params() {
+            Some(problem) if mode == ParamValidation::Error => Err(anyhow!(problem)),
+            Some(problem) => {
+                eprintln!("[neo4j] Warning: {}", problem);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct LuaNeoQuery(pub RwLock<NeoQuery>);
+
+impl LuaNeoQuery {
+    pub fn new(query: NeoQuery) -> Self {
+        Self(RwLock::new(query))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, NeoQuery> {
+        self.0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, NeoQuery> {
+        self.0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Validates the parameters and builds the driver query for execution.
+    pub fn prepare(&self) -> anyhow::Result<Query> {
+        let query = self.read();
+        query.validate(runtime::param_validation())?;
+        Ok(query.to_query())
+    }
+
+    /// Result columns named by the query's `RETURN` clause.
+    pub fn columns(&self) -> Option<Vec<String>> {
+        return_columns(&self.read().text)
+    }
+}
+
+register_lua_rstruct!(LuaNeoQuery, c"Neo4jQuery", &[
+    (c"Param", param),
+    (c"Params", params),
+    (c"Text", text),
+    (c"GetParams", get_params),
+    (c"Clone", clone),
+    (c"__tostring", to_string)
+]);
 
 #[lua_function]
 pub fn new_query(l: lua::State) -> anyhow::Result<i32> {
     // Argument 1: query string
-    let query_str = l.check_string(1)?;
+    let mut query_str = l.check_string(1)?;
+
+    // Templates are checked before rendering, their values are escaped
+    check_allowed(&query_str)?;
+
+    // Argument 3: identifiers for {{name}} placeholders
+    if !l.is_none_or_nil(3) {
+        let values = template_values(l, 3)?;
+        query_str = render_template(&query_str, &values)?;
+    }
 
-    let mut query = neo4rs::query(query_str.as_str());
+    let mut query = NeoQuery::new(query_str);
 
     // Catch bad parameter sets
     if !l.is_none_or_nil(2) && !l.is_table(2) {
@@ -27,13 +173,231 @@ pub fn new_query(l: lua::State) -> anyhow::Result<i32> {
 
     // Map parameters
     if l.is_table(2) {
-        let table = lua_table_to_boltmap(l, 2)?;
-        for (key, value) in table.value {
-            query = query.param(&key.value, value)
+        query.params = params_from_table(l, 2)?;
+
+        // A parameter table means the query is meant to be complete, so fail
+        // early in error mode. Warnings are left to execution to avoid
+        // reporting the same query twice.
+        if runtime::param_validation() == ParamValidation::Error {
+            query.validate(ParamValidation::Error)?;
         }
     }
 
-    l.push_struct::<LuaNeoQuery>(LuaNeoQuery(Arc::new(query)));
+    l.push_struct::<LuaNeoQuery>(LuaNeoQuery::new(query));
 
     Ok(1)
 }
+
+/// Converts a table of query parameters, naming the parameter that failed.
+pub fn params_from_table(l: lua::State, index: i32) -> anyhow::Result<BoltMap> {
+    if !l.is_table(index) {
+        return Err(Error::msg("Expected a table of parameters"));
+    }
+
+    let mut params = BoltMap::new();
+    unsafe {
+        l.push_nil();
+        while l.next(index) != 0 {
+            let key_type = l.lua_type(-2);
+            if key_type != LUA_TSTRING {
+                l.pop_n(2);
+                return Err(anyhow!(
+                    "Parameter names must be strings, got {}",
+                    lua_type_name(key_type)
+                ));
+            }
+            let key = l.get_string_unchecked(-2);
+
+            match lua_value_to_bolttype(l, -1, &key) {
+                Ok(value) => params.put(BoltString::from(key), value),
+                Err(err) => {
+                    l.pop_n(2);
+                    return Err(anyhow!("Invalid query parameter: {}", err));
+                }
+            }
+            l.pop_n(1);
+        }
+    }
+
+    Ok(params)
+}
+
+#[lua_function]
+pub fn param(l: lua::State) -> anyhow::Result<i32> {
+    let neo_query = l.get_struct::<LuaNeoQuery>(1)?;
+    let key = l.check_string(2)?;
+
+    // Setting a parameter to nil removes it
+    if l.is_none_or_nil(3) {
+        neo_query.write().params.value.remove(key.as_str());
+    } else {
+        let value = lua_value_to_bolttype(l, 3, &key)
+            .map_err(|err| anyhow!("Invalid query parameter: {}", err))?;
+        neo_query.write().params.put(BoltString::from(key), value);
+    }
+
+    // Return the query itself so calls can be chained
+    l.push_value(1);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn params(l: lua::State) -> anyhow::Result<i32> {
+    let neo_query = l.get_struct::<LuaNeoQuery>(1)?;
+
+    if !l.is_table(2) {
+        return Err(Error::msg("Params expects a table of parameters"));
+    }
+
+    let table = params_from_table(l, 2)?;
+    let mut query = neo_query.write();
+    for (key, value) in table.value {
+        query.params.put(key, value);
+    }
+
+    l.push_value(1);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn text(l: lua::State) -> anyhow::Result<i32> {
+    let neo_query = l.get_struct::<LuaNeoQuery>(1)?;
+
+    l.push_string(&neo_query.read().text);
+    Ok(1)
+}
+
+#[lua_function]
+pub fn get_params(l: lua::State) -> anyhow::Result<i32> {
+    let neo_query = l.get_struct::<LuaNeoQuery>(1)?;
+
+    boltmap_to_lua_table(l, &neo_query.read().params)?;
+    Ok(1)
+}
+
+#[lua_function]
+pub fn clone(l: lua::State) -> anyhow::Result<i32> {
+    let neo_query = l.get_struct::<LuaNeoQuery>(1)?;
+
+    let copy = neo_query.read().clone();
+    l.push_struct::<LuaNeoQuery>(LuaNeoQuery::new(copy));
+    Ok(1)
+}
+
+#[lua_function]
+pub fn to_string(l: lua::State) -> anyhow::Result<i32> {
+    let neo_query = l.get_struct::<LuaNeoQuery>(1)?;
+    let query = neo_query.read();
+
+    let mut keys: Vec<&BoltString> = query.params.value.keys().collect();
+    keys.sort_by(|a, b| a.value.cmp(&b.value));
+
+    let params = keys
+        .into_iter()
+        .map(|key| {
+            format!(
+                "{} = {}",
+                key.value,
+                describe_value(&query.params.value[key])
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    l.push_string(&format!("Neo4jQuery [{}] {{{}}}", query.text, params));
+    Ok(1)
+}
+
+fn describe_value(value: &BoltType) -> String {
+    match value {
+        BoltType::String(v) => format!("{:?}", v.value),
+        BoltType::Integer(v) => v.value.to_string(),
+        BoltType::Float(v) => v.value.to_string(),
+        BoltType::Boolean(v) => v.value.to_string(),
+        BoltType::Null(_) => "nil".to_string(),
+        BoltType::List(v) => format!(
+            "[{}]",
+            v.iter().map(describe_value).collect::<Vec<_>>().join(", ")
+        ),
+        BoltType::Map(v) => {
+            let mut entries: Vec<_> = v.value.iter().collect();
+            entries.sort_by(|a, b| a.0.value.cmp(&b.0.value));
+            format!(
+                "{{{}}}",
+                entries
+                    .into_iter()
+                    .map(|(key, value)| format!("{} = {}", key.value, describe_value(value)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        _ => "<value>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use neo4rs::{BoltInteger, BoltString, BoltType};
+
+    use super::*;
+
+    fn query(text: &str, params: &[&str]) -> NeoQuery {
+        let mut query = NeoQuery::new(text.to_string());
+        for name in params {
+            query.params.put(
+                BoltString::from(*name),
+                BoltType::Integer(BoltInteger::new(1)),
+            );
+        }
+        query
+    }
+
+    #[test]
+    fn matching_params_pass() {
+        let query = query(
+            "MATCH (n {id: $id}) RETURN n LIMIT $limit",
+            &["id", "limit"],
+        );
+        assert_eq!(query.check_params(), None);
+        assert!(query.validate(ParamValidation::Error).is_ok());
+    }
+
+    #[test]
+    fn missing_params_are_reported() {
+        let query = query("MATCH (n {id: $id}) RETURN n LIMIT $limit", &["id"]);
+        let problem = query.check_params().unwrap();
+        assert!(
+            problem.contains("missing parameters: $limit"),
+            "{}",
+            problem
+        );
+        assert!(!problem.contains("unused"), "{}", problem);
+    }
+
+    #[test]
+    fn unused_params_are_reported() {
+        let query = query("MATCH (n) RETURN n", &["b", "a"]);
+        let problem = query.check_params().unwrap();
+        assert!(problem.contains("unused parameters: a, b"), "{}", problem);
+        assert!(!problem.contains("missing"), "{}", problem);
+    }
+
+    #[test]
+    fn params_in_strings_and_comments_are_not_used() {
+        let query = query(
+            "MATCH (n) WHERE n.note = 'costs $price' RETURN n // $limit",
+            &["price"],
+        );
+        let problem = query.check_params().unwrap();
+        assert!(problem.contains("unused parameters: price"), "{}", problem);
+        assert!(!problem.contains("missing"), "{}", problem);
+    }
+
+    #[test]
+    fn validation_mode_decides_the_outcome() {
+        let query = query("RETURN $x", &[]);
+        assert!(query.validate(ParamValidation::Error).is_err());
+        assert!(query.validate(ParamValidation::Warning).is_ok());
+        assert!(query.validate(ParamValidation::Off).is_ok());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use gmod::{LUA_TBOOLEAN, lua, lua_function};
+use lazy_static::lazy_static;
+
+use crate::api::graph::{ConnectionInfo, GraphState, LuaNeoGraph, read_config};
+use crate::api::health::{HealthCheck, read_health_check, start_health_check};
+
+/// A graph shared between addons under a name.
+pub struct RegisteredGraph {
+    pub handle: LuaNeoGraph,
+    pub info: ConnectionInfo,
+    pub health_check: Option<HealthCheck>,
+}
+
+lazy_static! {
+    static ref GRAPHS: RwLock<BTreeMap<String, RegisteredGraph>> = RwLock::new(BTreeMap::new());
+}
+
+fn get_graph(name: &str) -> Option<LuaNeoGraph> {
+    GRAPHS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(name)
+        .map(|registered| registered.handle.share(false))
+}
+
+/// Unregisters a graph that is being closed.
+pub fn forget(state: &Arc<GraphState>) {
+    GRAPHS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .retain(|_, registered| !Arc::ptr_eq(&registered.handle.state, state));
+}
+
+/// Drops every registered graph so their pools close with the module.
+pub fn clear() {
+    GRAPHS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clear();
+}
+
+/// Runs `hook.Run("Neo4jGraphRegistered", name, graph)`.
+fn run_registered_hook(l: lua::State, name: &str, graph: LuaNeoGraph) {
+    l.get_global(c"hook");
+    if !l.is_table(-1) {
+        l.pop();
+        return;
+    }
+
+    l.get_field(-1, c"Run");
+    l.pcall_ignore(|| {
+        l.push_string("Neo4jGraphRegistered");
+        l.push_string(name);
+        l.push_struct::<LuaNeoGraph>(graph);
+        0
+    });
+    l.pop();
+}
+
+/// `neo4j.Register(name, uri, user, password, options)`. Registering the same
+/// name again with the same connection gives back the existing pool, so every
+/// addon can register the graph it needs without knowing who loads first.
+#[lua_function]
+pub fn register(l: lua::State) -> anyhow::Result<i32> {
+    let name = l.check_string(1)?;
+    let (config, info) = read_config(l, 2)?;
+    let health_check = read_health_check(l, 5)?;
+
+    let mut replace = false;
+    if l.is_table(5) {
+        l.get_field(5, c"replace");
+        if l.lua_type(-1) == LUA_TBOOLEAN {
+            replace = l.check_boolean(-1)?;
+        }
+        l.pop_n(1);
+    }
+
+    if !replace {
+        let graphs = GRAPHS
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(registered) = graphs.get(&name) {
+            let mut differences = registered.info.differences(&info);
+            if registered.health_check != health_check {
+                differences.push("health_check");
+            }
+            if !differences.is_empty() {
+                return Err(anyhow!(
+                    "Graph '{}' is already registered with a different {}; pass replace = true \
+                     to replace it",
+                    name,
+                    differences.join(", ")
+                ));
+            }
+
+            l.push_struct::<LuaNeoGraph>(registered.handle.share(false));
+            return Ok(1);
+        }
+    }
+
+    let graph = LuaNeoGraph::new(config)?;
+    if let Some(health_check) = health_check {
+        start_health_check(&graph, health_check);
+    }
+    GRAPHS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(
+            name.clone(),
+            RegisteredGraph {
+                handle: graph.share(false),
+                info,
+                health_check,
+            },
+        );
+    // A replaced graph keeps working for handles that still hold it
+    println!("[neo4j] Registered graph '{}'", name);
+
+    run_registered_hook(l, &name, graph.share(false));
+
+    l.push_struct::<LuaNeoGraph>(graph);
+    Ok(1)
+}
+
+/// `neo4j.Get(name)` returns the registered graph or nil.
+#[lua_function]
+pub fn get(l: lua::State) -> anyhow::Result<i32> {
+    let name = l.check_string(1)?;
+
+    match get_graph(&name) {
+        Some(graph) => l.push_struct::<LuaNeoGraph>(graph),
+        None => l.push_nil(),
+    }
+
+    Ok(1)
+}
+
+#[lua_function]
+pub fn list(l: lua::State) -> anyhow::Result<i32> {
+    let graphs = GRAPHS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    l.create_table(graphs.len() as i32, 0);
+    for (i, (name, registered)) in graphs.iter().enumerate() {
+        l.create_table(0, 4);
+
+        l.push_string("name");
+        l.push_string(name);
+        l.raw_set_table(-3);
+
+        l.push_string("uri");
+        l.push_string(&registered.info.uri);
+        l.raw_set_table(-3);
+
+        l.push_string("user");
+        l.push_string(&registered.info.user);
+        l.raw_set_table(-3);
+
+        if let Some(db) = &registered.info.db {
+            l.push_string("db");
+            l.push_string(db);
+            l.raw_set_table(-3);
+        }
+
+        l.raw_seti(-2, i as i32 + 1);
+    }
+
+    Ok(1)
+}
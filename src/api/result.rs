@@ -1,22 +1,189 @@
-use gmod::{LuaReference, wait_lua_tick};
+use anyhow::anyhow;
+use gmod::{LuaReference, lua, wait_lua_tick};
 use neo4rs::BoltMap;
 
-use crate::mapping::boltmap_to_lua_table;
+use crate::mapping::{boltmap_to_lua_table, map_type_to_lua};
+
+/// How the rows of a result are handed to Lua.
+pub enum ResultShape {
+    /// An array of row tables
+    Rows,
+    /// The only row, failing unless there is exactly one
+    Single,
+    /// The first column of the first row, or nil
+    Scalar,
+    /// An array with the values of a single column
+    Column(String),
+    /// `{ cols = {...}, data = {...} }` with one array of values per column
+    Columnar,
+    /// An array of rows, each an array of values in column order
+    Arrays,
+}
+
+/// Rows of a result together with its column names.
+pub struct ResultSet {
+    pub keys: Vec<String>,
+    pub rows: Vec<BoltMap>,
+}
+
+impl ResultSet {
+    /// The driver does not expose the column order, so `declared` holds the
+    /// columns parsed from the query text. They are only trusted if they match
+    /// the returned rows, otherwise the keys are sorted.
+    pub fn new(rows: Vec<BoltMap>, declared: Option<Vec<String>>) -> Self {
+        let keys = match (rows.first(), declared) {
+            (None, declared) => declared.unwrap_or_default(),
+            (Some(row), Some(declared))
+                if declared.len() == row.len()
+                    && declared
+                        .iter()
+                        .all(|key| row.value.contains_key(key.as_str())) =>
+            {
+                declared
+            }
+            (Some(row), _) => {
+                let mut keys: Vec<String> = row.value.keys().map(|key| key.value.clone()).collect();
+                keys.sort();
+                keys
+            }
+        };
+
+        Self { keys, rows }
+    }
+}
+
+impl ResultShape {
+    /// Checks the rows fit the shape, so problems reach the callback as errors.
+    pub fn check(&self, set: &ResultSet) -> anyhow::Result<()> {
+        match self {
+            ResultShape::Single if set.rows.len() != 1 => {
+                Err(anyhow!("Expected exactly one row, got {}", set.rows.len()))
+            }
+            ResultShape::Column(name) if !set.rows.is_empty() && !set.keys.contains(name) => {
+                Err(anyhow!(
+                    "Result has no column '{}' (columns: {})",
+                    name,
+                    set.keys.join(", ")
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn push(&self, l: lua::State, set: &ResultSet) -> anyhow::Result<()> {
+        let rows = &set.rows;
+        match self {
+            ResultShape::Rows => push_rows(l, rows)?,
+            ResultShape::Single => match rows.first() {
+                Some(row) => boltmap_to_lua_table(l, row)?,
+                None => l.push_nil(),
+            },
+            ResultShape::Scalar => {
+                let value = rows
+                    .first()
+                    .zip(set.keys.first())
+                    .and_then(|(row, column)| row.value.get(column.as_str()));
+                match value {
+                    Some(value) => map_type_to_lua(l, value)?,
+                    None => l.push_nil(),
+                }
+            }
+            ResultShape::Column(name) => push_column(l, rows, name)?,
+            ResultShape::Arrays => {
+                l.new_table();
+                for (i, row) in rows.iter().enumerate() {
+                    l.new_table();
+                    for (j, column) in set.keys.iter().enumerate() {
+                        if let Some(value) = row.value.get(column.as_str()) {
+                            map_type_to_lua(l, value)?;
+                            l.raw_seti(-2, j as i32 + 1);
+                        }
+                    }
+                    l.raw_seti(-2, i as i32 + 1);
+                }
+            }
+            ResultShape::Columnar => {
+                let columns = &set.keys;
+
+                l.new_table();
+                l.push_string("cols");
+                l.new_table();
+                for (i, column) in columns.iter().enumerate() {
+                    l.push_string(column);
+                    l.raw_seti(-2, i as i32 + 1);
+                }
+                l.raw_set_table(-3);
+
+                l.push_string("data");
+                l.new_table();
+                for (i, column) in columns.iter().enumerate() {
+                    push_column(l, rows, column)?;
+                    l.raw_seti(-2, i as i32 + 1);
+                }
+                l.raw_set_table(-3);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn push_column(l: lua::State, rows: &[BoltMap], column: &str) -> anyhow::Result<()> {
+    l.new_table();
+    for (i, row) in rows.iter().enumerate() {
+        // Missing values leave a hole rather than shifting the rest
+        if let Some(value) = row.value.get(column) {
+            map_type_to_lua(l, value)?;
+            l.raw_seti(-2, i as i32 + 1);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn push_rows(l: lua::State, rows: &[BoltMap]) -> anyhow::Result<()> {
+    l.new_table();
+    for (i, item) in rows.iter().enumerate() {
+        boltmap_to_lua_table(l, item)?;
+        l.raw_seti(-2, i as i32 + 1);
+    }
+
+    Ok(())
+}
+
+/// Pushes the summary handed to callbacks after the result.
+pub fn push_summary(l: lua::State, set: &ResultSet) {
+    l.new_table();
+    l.push_string("keys");
+    l.new_table();
+    for (i, key) in set.keys.iter().enumerate() {
+        l.push_string(key);
+        l.raw_seti(-2, i as i32 + 1);
+    }
+    l.raw_set_table(-3);
+}
+
+pub fn dispatch_callback(callback: LuaReference, results: anyhow::Result<ResultSet>) {
+    dispatch_shaped_callback(callback, ResultShape::Rows, results);
+}
+
+pub fn dispatch_shaped_callback(
+    callback: LuaReference,
+    shape: ResultShape,
+    results: anyhow::Result<ResultSet>,
+) {
+    let results = results.and_then(|set| shape.check(&set).map(|_| set));
 
-pub fn dispatch_callback(callback: LuaReference, results: anyhow::Result<Vec<BoltMap>>) {
     // Dispatch the callback
     wait_lua_tick(move |l| {
         let _ = l.pcall_func_ref(callback, || match results {
             Ok(output) => {
                 // No error, so first value is nil
                 l.push_nil();
-                l.new_table();
-                for (i, item) in output.iter().enumerate() {
-                    let _ = boltmap_to_lua_table(l, item)
-                        .map_err(|e| panic!("Could not convert bolt map to Lua table: {}", e));
-
-                    l.raw_seti(-2, i as i32 + 1);
-                }
+                let _ = shape
+                    .push(l, &output)
+                    .map_err(|e| panic!("Could not convert bolt map to Lua table: {}", e));
+                push_summary(l, &output);
 
                 1
             }
@@ -29,3 +196,55 @@ pub fn dispatch_callback(callback: LuaReference, results: anyhow::Result<Vec<Bol
         });
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use neo4rs::{BoltInteger, BoltString, BoltType};
+
+    use super::*;
+
+    fn row(keys: &[&str]) -> BoltMap {
+        let mut row = BoltMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            row.put(
+                BoltString::from(*key),
+                BoltType::Integer(BoltInteger::new(i as i64)),
+            );
+        }
+        row
+    }
+
+    fn columns(names: &[&str]) -> Option<Vec<String>> {
+        Some(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn declared_columns_keep_their_order() {
+        let set = ResultSet::new(vec![row(&["b", "a"])], columns(&["b", "a"]));
+        assert_eq!(set.keys, ["b", "a"]);
+    }
+
+    #[test]
+    fn unknown_columns_fall_back_to_sorted_row_keys() {
+        let set = ResultSet::new(vec![row(&["name", "age"])], None);
+        assert_eq!(set.keys, ["age", "name"]);
+    }
+
+    #[test]
+    fn mismatched_columns_fall_back_to_sorted_row_keys() {
+        let set = ResultSet::new(vec![row(&["x", "n"])], columns(&["n"]));
+        assert_eq!(set.keys, ["n", "x"]);
+
+        let set = ResultSet::new(vec![row(&["x", "n"])], columns(&["n", "y"]));
+        assert_eq!(set.keys, ["n", "x"]);
+    }
+
+    #[test]
+    fn empty_results_use_the_declared_columns() {
+        assert_eq!(
+            ResultSet::new(Vec::new(), columns(&["b", "a"])).keys,
+            ["b", "a"]
+        );
+        assert!(ResultSet::new(Vec::new(), None).keys.is_empty());
+    }
+}
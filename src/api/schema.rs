@@ -0,0 +1,613 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, anyhow};
+use gmod::{LuaReference, lua, lua_function, wait_lua_tick};
+use neo4rs::{BoltList, BoltMap, BoltNull, BoltString, BoltType, Graph, query};
+
+use crate::api::allowlist::check_startup;
+use crate::api::graph::{LuaNeoGraph, handle_graph_execution};
+use crate::cypher::{escape_identifier, validate_identifier};
+use crate::mapping::boltmap_to_lua_table;
+use crate::runtime;
+
+/// What a constraint or index is defined on.
+pub enum Entity {
+    Node(String),
+    Relationship(String),
+}
+
+impl Entity {
+    fn name(&self) -> &str {
+        match self {
+            Entity::Node(name) | Entity::Relationship(name) => name,
+        }
+    }
+
+    /// The `FOR ...` pattern, binding the entity to `e`.
+    fn pattern(&self) -> String {
+        match self {
+            Entity::Node(label) => format!("(e:{})", escape_identifier(label)),
+            Entity::Relationship(rel_type) => format!("()-[e:{}]-()", escape_identifier(rel_type)),
+        }
+    }
+
+    fn is_node(&self) -> bool {
+        matches!(self, Entity::Node(_))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ConstraintKind {
+    Unique,
+    Key,
+    NotNull,
+}
+
+impl ConstraintKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().replace(['-', ' '], "_").as_str() {
+            "unique" => Some(Self::Unique),
+            "key" | "node_key" | "relationship_key" => Some(Self::Key),
+            "not_null" | "exists" => Some(Self::NotNull),
+            _ => None,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Unique => "unique",
+            Self::Key => "key",
+            Self::NotNull => "not_null",
+        }
+    }
+
+    /// Types `SHOW CONSTRAINTS` reports for this kind, across server versions.
+    fn server_types(self, entity: &Entity) -> &'static [&'static str] {
+        match (self, entity.is_node()) {
+            (Self::Unique, true) => &["UNIQUENESS", "NODE_PROPERTY_UNIQUENESS"],
+            (Self::Unique, false) => &[
+                "RELATIONSHIP_UNIQUENESS",
+                "RELATIONSHIP_PROPERTY_UNIQUENESS",
+            ],
+            (Self::Key, true) => &["NODE_KEY"],
+            (Self::Key, false) => &["RELATIONSHIP_KEY"],
+            (Self::NotNull, true) => &["NODE_PROPERTY_EXISTENCE"],
+            (Self::NotNull, false) => &["RELATIONSHIP_PROPERTY_EXISTENCE"],
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum IndexKind {
+    Range,
+    Text,
+    FullText,
+    Point,
+    Vector,
+}
+
+impl IndexKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().replace(['-', ' '], "_").as_str() {
+            "range" => Some(Self::Range),
+            "text" => Some(Self::Text),
+            "fulltext" | "full_text" => Some(Self::FullText),
+            "point" => Some(Self::Point),
+            "vector" => Some(Self::Vector),
+            _ => None,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Range => "range",
+            Self::Text => "text",
+            Self::FullText => "fulltext",
+            Self::Point => "point",
+            Self::Vector => "vector",
+        }
+    }
+
+    fn server_type(self) -> &'static str {
+        match self {
+            Self::Range => "RANGE",
+            Self::Text => "TEXT",
+            Self::FullText => "FULLTEXT",
+            Self::Point => "POINT",
+            Self::Vector => "VECTOR",
+        }
+    }
+}
+
+pub enum SchemaKind {
+    Constraint(ConstraintKind),
+    Index {
+        kind: IndexKind,
+        /// `OPTIONS {...}` text, only used by vector indexes
+        options: Option<String>,
+    },
+}
+
+/// A constraint or index as declared from Lua.
+pub struct SchemaSpec {
+    pub kind: SchemaKind,
+    pub entity: Entity,
+    pub properties: Vec<String>,
+    pub name: String,
+}
+
+impl SchemaSpec {
+    /// Names follow `<kind>_<label>_<properties>` unless one is given.
+    fn default_name(prefix: &str, entity: &Entity, properties: &[String]) -> String {
+        format!("{}_{}_{}", prefix, entity.name(), properties.join("_"))
+    }
+
+    fn create_statement(&self) -> String {
+        let name = escape_identifier(&self.name);
+        let pattern = self.entity.pattern();
+        let properties: Vec<String> = self
+            .properties
+            .iter()
+            .map(|property| format!("e.{}", escape_identifier(property)))
+            .collect();
+        let properties = properties.join(", ");
+
+        match &self.kind {
+            SchemaKind::Constraint(kind) => {
+                let requirement = match kind {
+                    ConstraintKind::Unique => "IS UNIQUE",
+                    ConstraintKind::Key if self.entity.is_node() => "IS NODE KEY",
+                    ConstraintKind::Key => "IS RELATIONSHIP KEY",
+                    ConstraintKind::NotNull => "IS NOT NULL",
+                };
+                let properties = if self.properties.len() == 1 {
+                    properties
+                } else {
+                    format!("({})", properties)
+                };
+                format!(
+                    "CREATE CONSTRAINT {} IF NOT EXISTS FOR {} REQUIRE {} {}",
+                    name, pattern, properties, requirement
+                )
+            }
+            SchemaKind::Index { kind, options } => {
+                let keyword = match kind {
+                    IndexKind::Range => "",
+                    IndexKind::Text => "TEXT ",
+                    IndexKind::FullText => "FULLTEXT ",
+                    IndexKind::Point => "POINT ",
+                    IndexKind::Vector => "VECTOR ",
+                };
+                let on = match kind {
+                    IndexKind::FullText => format!("ON EACH [{}]", properties),
+                    _ => format!("ON ({})", properties),
+                };
+                let options = options
+                    .as_ref()
+                    .map(|options| format!(" OPTIONS {}", options))
+                    .unwrap_or_default();
+                format!(
+                    "CREATE {}INDEX {} IF NOT EXISTS FOR {} {}{}",
+                    keyword, name, pattern, on, options
+                )
+            }
+        }
+    }
+
+    fn matches(&self, row: &BoltMap) -> bool {
+        let server_type: String = row.get("type").unwrap_or_default();
+        let type_matches = match &self.kind {
+            SchemaKind::Constraint(kind) => kind
+                .server_types(&self.entity)
+                .contains(&server_type.as_str()),
+            SchemaKind::Index { kind, .. } => kind.server_type() == server_type,
+        };
+
+        let entity_type: String = row.get("entityType").unwrap_or_default();
+        let labels: Vec<String> = row
+            .get::<Option<Vec<String>>>("labelsOrTypes")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let properties: Vec<String> = row
+            .get::<Option<Vec<String>>>("properties")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
+        type_matches
+            && (entity_type == "NODE") == self.entity.is_node()
+            && labels == [self.entity.name()]
+            && properties == self.properties
+    }
+}
+
+/// Reads a string or an array of strings from the top of the stack.
+fn read_names(l: lua::State, field: &str) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if l.is_string(-1) {
+        names.push(l.get_string_unchecked(-1));
+    } else if l.is_table(-1) {
+        let table = l.get_top();
+        for i in 1..=l.len(table) {
+            l.raw_geti(table, i);
+            if !l.is_string(-1) {
+                l.pop();
+                return Err(anyhow!("{}[{}] must be a string", field, i));
+            }
+            names.push(l.get_string_unchecked(-1));
+            l.pop();
+        }
+    }
+
+    Ok(names)
+}
+
+fn read_string_field(l: lua::State, index: i32, field: &std::ffi::CStr) -> Option<String> {
+    l.get_field(index, field);
+    let value = if l.is_string(-1) {
+        Some(l.get_string_unchecked(-1))
+    } else {
+        None
+    };
+    l.pop_n(1);
+    value
+}
+
+fn read_spec(l: lua::State, index: i32, constraint: bool) -> anyhow::Result<SchemaSpec> {
+    if !l.is_table(index) {
+        return Err(anyhow!("Expected a table describing the schema object"));
+    }
+
+    let entity = match (
+        read_string_field(l, index, c"label"),
+        read_string_field(l, index, c"relationship"),
+    ) {
+        (Some(label), None) => Entity::Node(label),
+        (None, Some(rel_type)) => Entity::Relationship(rel_type),
+        _ => return Err(anyhow!("Set exactly one of 'label' or 'relationship'")),
+    };
+
+    let mut properties = Vec::new();
+    for field in [c"property", c"properties"] {
+        l.get_field(index, field);
+        let names = read_names(l, &field.to_string_lossy());
+        l.pop_n(1);
+        properties.extend(names?);
+    }
+    if properties.is_empty() {
+        return Err(anyhow!("Set 'property' or 'properties'"));
+    }
+
+    let kind_name = read_string_field(l, index, c"type");
+    let kind = if constraint {
+        let kind_name = kind_name.as_deref().unwrap_or("unique");
+        let kind = ConstraintKind::parse(kind_name).ok_or_else(|| {
+            anyhow!(
+                "Unknown constraint type '{}' (expected unique, key or not_null)",
+                kind_name
+            )
+        })?;
+        if matches!(kind, ConstraintKind::NotNull) && properties.len() != 1 {
+            return Err(anyhow!("not_null constraints take exactly one property"));
+        }
+        SchemaKind::Constraint(kind)
+    } else {
+        let kind_name = kind_name.as_deref().unwrap_or("range");
+        let kind = IndexKind::parse(kind_name).ok_or_else(|| {
+            anyhow!(
+                "Unknown index type '{}' (expected range, text, fulltext, point or vector)",
+                kind_name
+            )
+        })?;
+        if matches!(kind, IndexKind::Text | IndexKind::Point | IndexKind::Vector)
+            && properties.len() != 1
+        {
+            return Err(anyhow!(
+                "{} indexes take exactly one property",
+                kind.prefix()
+            ));
+        }
+
+        let options = if kind == IndexKind::Vector {
+            l.get_field(index, c"dimensions");
+            let dimensions = l.is_number(-1).then(|| l.to_number(-1));
+            l.pop_n(1);
+            let dimensions = dimensions
+                .filter(|dimensions| *dimensions >= 1.0)
+                .ok_or_else(|| anyhow!("Vector indexes need a positive 'dimensions'"))?;
+
+            let similarity = read_string_field(l, index, c"similarity")
+                .unwrap_or_else(|| "cosine".to_string())
+                .to_ascii_lowercase();
+            if similarity != "cosine" && similarity != "euclidean" {
+                return Err(anyhow!(
+                    "Unknown vector similarity '{}' (expected cosine or euclidean)",
+                    similarity
+                ));
+            }
+
+            Some(format!(
+                "{{indexConfig: {{`vector.dimensions`: {}, `vector.similarity_function`: '{}'}}}}",
+                dimensions as u64, similarity
+            ))
+        } else {
+            None
+        };
+        SchemaKind::Index { kind, options }
+    };
+
+    let prefix = match &kind {
+        SchemaKind::Constraint(kind) => kind.prefix(),
+        SchemaKind::Index { kind, .. } => kind.prefix(),
+    };
+    let name = read_string_field(l, index, c"name")
+        .unwrap_or_else(|| SchemaSpec::default_name(prefix, &entity, &properties));
+
+    // Names end up in query text, so check them before any query is built
+    for name in [entity.name(), name.as_str()]
+        .into_iter()
+        .chain(properties.iter().map(String::as_str))
+    {
+        validate_identifier(name)?;
+    }
+
+    Ok(SchemaSpec {
+        kind,
+        entity,
+        properties,
+        name,
+    })
+}
+
+/// Creates the constraint or index unless an equivalent one exists, returning
+/// its name and whether it was created.
+async fn ensure(graph: Graph, spec: SchemaSpec) -> anyhow::Result<(String, bool)> {
+    let show = match spec.kind {
+        SchemaKind::Constraint(_) => {
+            "SHOW CONSTRAINTS YIELD name, type, entityType, labelsOrTypes, properties"
+        }
+        SchemaKind::Index { .. } => {
+            "SHOW INDEXES YIELD name, type, entityType, labelsOrTypes, properties"
+        }
+    };
+
+    let existing = handle_graph_execution(graph.clone(), query(show)).await?;
+    if let Some(row) = existing.iter().find(|row| spec.matches(row)) {
+        let name: String = row.get("name")?;
+        return Ok((name, false));
+    }
+    if existing.iter().any(|row| {
+        row.get::<String>("name")
+            .is_ok_and(|name| name == spec.name)
+    }) {
+        return Err(anyhow!(
+            "A schema object named '{}' already exists with a different definition",
+            spec.name
+        ));
+    }
+
+    graph.run(query(&spec.create_statement())).await?;
+    Ok((spec.name, true))
+}
+
+fn dispatch_ensure(callback: Option<LuaReference>, result: anyhow::Result<(String, bool)>) {
+    let Some(callback) = callback else {
+        // Without a callback, report to the console
+        match result {
+            Ok((name, true)) => println!("[neo4j] Created {}", name),
+            Ok((_, false)) => {}
+            Err(err) => eprintln!("[neo4j] {}", err),
+        }
+        return;
+    };
+
+    wait_lua_tick(move |l| {
+        let _ = l.pcall_func_ref(callback, || match &result {
+            Ok((name, created)) => {
+                l.push_nil();
+                l.new_table();
+                l.push_string("name");
+                l.push_string(name);
+                l.raw_set_table(-3);
+                l.push_string("created");
+                l.push_boolean(*created);
+                l.raw_set_table(-3);
+                1
+            }
+            Err(err) => {
+                l.push_string(&err.to_string());
+                l.push_nil();
+                1
+            }
+        });
+    });
+}
+
+fn ensure_schema(l: lua::State, constraint: bool) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    graph_container.check_writable("Changing the schema")?;
+    // Schema statements run directly, outside the allowlist, so strict mode
+    // keeps them to startup
+    check_startup(l, "Changing the schema")?;
+    let spec = read_spec(l, 2, constraint)?;
+    let callback = if l.is_none_or_nil(3) {
+        None
+    } else {
+        Some(l.check_function(3)?)
+    };
+
+    let graph = graph_container.graph()?;
+    runtime::run_async(graph_container.track(async move {
+        let result = ensure(graph, spec).await;
+
+        dispatch_ensure(callback, result);
+    }));
+
+    Ok(0)
+}
+
+#[lua_function]
+pub fn ensure_constraint(l: lua::State) -> anyhow::Result<i32> {
+    ensure_schema(l, true).map_err(|err| Error::msg(format!("EnsureConstraint: {}", err)))
+}
+
+#[lua_function]
+pub fn ensure_index(l: lua::State) -> anyhow::Result<i32> {
+    ensure_schema(l, false).map_err(|err| Error::msg(format!("EnsureIndex: {}", err)))
+}
+
+async fn column_list(graph: &Graph, text: &str, column: &str) -> anyhow::Result<BoltType> {
+    let rows = handle_graph_execution(graph.clone(), query(text)).await?;
+    let values = rows
+        .into_iter()
+        .filter_map(|mut row| row.value.remove(column))
+        .collect();
+
+    Ok(BoltType::List(BoltList { value: values }))
+}
+
+async fn row_list(graph: &Graph, text: &str) -> anyhow::Result<BoltType> {
+    let rows = handle_graph_execution(graph.clone(), query(text)).await?;
+    let values = rows.into_iter().map(BoltType::Map).collect();
+
+    Ok(BoltType::List(BoltList { value: values }))
+}
+
+/// Groups the rows of `db.schema.*TypeProperties` into
+/// `{ [label] = { [property] = { types } } }`.
+fn group_properties(rows: Vec<BoltMap>, names: impl Fn(&BoltMap) -> Vec<String>) -> BoltType {
+    let mut grouped: BTreeMap<String, BoltMap> = BTreeMap::new();
+    for row in rows {
+        let property: Option<String> = row.get("propertyName").ok().flatten();
+        let types = row
+            .value
+            .get("propertyTypes")
+            .cloned()
+            .unwrap_or(BoltType::Null(BoltNull));
+
+        for name in names(&row) {
+            let properties = grouped.entry(name).or_default();
+            if let Some(property) = &property {
+                properties.put(BoltString::from(property.as_str()), types.clone());
+            }
+        }
+    }
+
+    let mut map = BoltMap::new();
+    for (name, properties) in grouped {
+        map.put(BoltString::from(name.as_str()), BoltType::Map(properties));
+    }
+    BoltType::Map(map)
+}
+
+async fn read_schema(graph: Graph) -> anyhow::Result<BoltMap> {
+    let mut schema = BoltMap::new();
+
+    let labels = column_list(
+        &graph,
+        "CALL db.labels() YIELD label RETURN label ORDER BY label",
+        "label",
+    );
+    schema.put(BoltString::from("labels"), labels.await?);
+
+    let relationship_types = column_list(
+        &graph,
+        "CALL db.relationshipTypes() YIELD relationshipType \
+         RETURN relationshipType ORDER BY relationshipType",
+        "relationshipType",
+    );
+    schema.put(
+        BoltString::from("relationship_types"),
+        relationship_types.await?,
+    );
+
+    let property_keys = column_list(
+        &graph,
+        "CALL db.propertyKeys() YIELD propertyKey RETURN propertyKey ORDER BY propertyKey",
+        "propertyKey",
+    );
+    schema.put(BoltString::from("property_keys"), property_keys.await?);
+
+    let node_properties = handle_graph_execution(
+        graph.clone(),
+        query(
+            "CALL db.schema.nodeTypeProperties() \
+             YIELD nodeLabels, propertyName, propertyTypes RETURN *",
+        ),
+    )
+    .await?;
+    schema.put(
+        BoltString::from("node_properties"),
+        group_properties(node_properties, |row| {
+            row.get("nodeLabels").unwrap_or_default()
+        }),
+    );
+
+    let relationship_properties = handle_graph_execution(
+        graph.clone(),
+        query(
+            "CALL db.schema.relTypeProperties() \
+             YIELD relType, propertyName, propertyTypes RETURN *",
+        ),
+    )
+    .await?;
+    schema.put(
+        BoltString::from("relationship_properties"),
+        group_properties(relationship_properties, |row| {
+            // Reported as ":`TYPE`"
+            let rel_type: String = row.get("relType").unwrap_or_default();
+            vec![
+                rel_type
+                    .trim_start_matches(':')
+                    .trim_matches('`')
+                    .replace("``", "`"),
+            ]
+        }),
+    );
+
+    let indexes = row_list(
+        &graph,
+        "SHOW INDEXES YIELD name, type, entityType, labelsOrTypes, properties, state \
+         RETURN * ORDER BY name",
+    );
+    schema.put(BoltString::from("indexes"), indexes.await?);
+
+    let constraints = row_list(
+        &graph,
+        "SHOW CONSTRAINTS YIELD name, type, entityType, labelsOrTypes, properties \
+         RETURN * ORDER BY name",
+    );
+    schema.put(BoltString::from("constraints"), constraints.await?);
+
+    Ok(schema)
+}
+
+#[lua_function]
+pub fn schema(l: lua::State) -> anyhow::Result<i32> {
+    let graph_container = l.get_struct::<LuaNeoGraph>(1)?;
+    let callback = l.check_function(2)?;
+
+    let graph = graph_container.graph()?;
+    runtime::run_async(graph_container.track(async move {
+        let result = read_schema(graph).await;
+
+        wait_lua_tick(move |l| {
+            let _ = l.pcall_func_ref(callback, || match &result {
+                Ok(schema) => {
+                    l.push_nil();
+                    let _ = boltmap_to_lua_table(l, schema)
+                        .map_err(|e| panic!("Could not convert bolt map to Lua table: {}", e));
+                    1
+                }
+                Err(err) => {
+                    l.push_string(&err.to_string());
+                    l.push_nil();
+                    1
+                }
+            });
+        });
+    }));
+
+    Ok(0)
+}
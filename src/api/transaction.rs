@@ -5,18 +5,52 @@ use gmod::{lua, lua_function, register_lua_rstruct};
 use neo4rs::{BoltMap, Query, Txn};
 use tokio::sync::Mutex;
 
+use crate::api::graph::GraphState;
+use crate::api::promise::LuaNeoPromise;
 use crate::api::query::LuaNeoQuery;
-use crate::api::result::dispatch_callback;
+use crate::api::result::{ResultSet, dispatch_callback};
 use crate::runtime::{self};
 
-pub struct LuaNeoTxn(pub Arc<Mutex<Option<Txn>>>);
+pub struct LuaNeoTxn {
+    txn: Arc<Mutex<Option<Txn>>>,
+    /// Checked on every call, so the transaction stops once the graph is closed
+    graph: Arc<GraphState>,
+}
+
+impl LuaNeoTxn {
+    pub fn new(graph: Arc<GraphState>, txn: Txn) -> Self {
+        Self {
+            txn: Arc::new(Mutex::new(Some(txn))),
+            graph,
+        }
+    }
+
+    /// Fails once the graph is closed, rolling the transaction back so it
+    /// gives its connection up.
+    fn check_open(&self) -> anyhow::Result<()> {
+        if let Err(err) = self.graph.graph() {
+            let txn = self.txn.clone();
+            runtime::run_async(async move {
+                let txn = txn.lock().await.take();
+                if let Some(txn) = txn
+                    && let Err(err) = txn.rollback().await
+                {
+                    eprintln!("[neo4j] Rollback after Close failed: {}", err);
+                }
+            });
+            return Err(err);
+        }
+        Ok(())
+    }
+}
 
 register_lua_rstruct!(LuaNeoTxn, c"Neo4jTransaction", &[
     (c"Execute", execute),
+    (c"ExecuteAsync", execute_async),
     (c"Commit", commit),
 ]);
 
-async fn handle_execution<'a>(
+pub async fn handle_execution<'a>(
     mut guard: tokio::sync::MutexGuard<'a, Option<Txn>>,
     query: Query,
 ) -> anyhow::Result<Vec<BoltMap>> {
@@ -57,32 +91,62 @@ async fn handle_execution<'a>(
 pub fn execute(l: lua::State) -> anyhow::Result<i32> {
     let neo_tx = l.get_struct::<LuaNeoTxn>(1)?;
     let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
-    let callback = l.check_function(3)?;
 
-    let tx_mutex = neo_tx.0.clone();
-    let arc_query = neo_query.0.clone();
+    neo_tx.check_open()?;
+    let tx_mutex = neo_tx.txn.clone();
+    let query = neo_query.prepare()?;
 
-    runtime::run_async(async move {
-        let results = {
+    // Without a callback, hand back a promise instead
+    if l.is_none_or_nil(3) {
+        let promise = LuaNeoPromise::spawn(neo_tx.graph.track(async move {
             let guard = tx_mutex.lock().await;
-            let query = (*arc_query).clone();
+            handle_execution(guard, query).await
+        }));
+        l.push_struct::<LuaNeoPromise>(promise);
+        return Ok(1);
+    }
+    let callback = l.check_function(3)?;
+    let columns = neo_query.columns();
 
+    runtime::run_async(neo_tx.graph.track(async move {
+        let results = {
+            let guard = tx_mutex.lock().await;
             handle_execution(guard, query).await
-        };
+        }
+        .map(|rows| ResultSet::new(rows, columns));
 
         dispatch_callback(callback, results);
-    });
+    }));
 
     Ok(0)
 }
 
+#[lua_function]
+pub fn execute_async(l: lua::State) -> anyhow::Result<i32> {
+    let neo_tx = l.get_struct::<LuaNeoTxn>(1)?;
+    let neo_query = l.get_struct::<LuaNeoQuery>(2)?;
+
+    neo_tx.check_open()?;
+    let tx_mutex = neo_tx.txn.clone();
+    let query = neo_query.prepare()?;
+
+    let promise = LuaNeoPromise::spawn(neo_tx.graph.track(async move {
+        let guard = tx_mutex.lock().await;
+        handle_execution(guard, query).await
+    }));
+    l.push_struct::<LuaNeoPromise>(promise);
+
+    Ok(1)
+}
+
 #[lua_function]
 pub fn commit(l: lua::State) -> anyhow::Result<i32> {
     let neo_tx = l.get_struct::<LuaNeoTxn>(1)?;
 
-    let tx_mutex = neo_tx.0.clone();
+    neo_tx.check_open()?;
+    let tx_mutex = neo_tx.txn.clone();
 
-    runtime::run_async(async move {
+    runtime::run_async(neo_tx.graph.track(async move {
         let mut guard = tx_mutex.lock().await;
         if let Some(txn) = guard.take() {
             println!("Commit tx");
@@ -92,7 +156,7 @@ pub fn commit(l: lua::State) -> anyhow::Result<i32> {
                 "Could not commit transaction".to_string(),
             ))
         }
-    });
+    }));
 
     Ok(0)
 }
@@ -0,0 +1,749 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::anyhow;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(String),
+    Ident(String),
+    Param(String),
+    Literal,
+    Symbol(char),
+}
+
+/// A token with the byte range it covers in the query text.
+#[derive(Debug, Clone)]
+pub struct Spanned {
+    pub token: Token,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Spanned {
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.token, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Splits Cypher text into tokens, skipping whitespace and comments.
+/// This is only as smart as the helpers in this crate need it to be.
+pub fn tokenize(text: &str) -> Vec<Spanned> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let char_at = |i: usize| chars.get(i).map(|(_, c)| *c);
+    let byte_at = |i: usize| chars.get(i).map(|(b, _)| *b).unwrap_or(text.len());
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let next = char_at(i + 1);
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // Line comment
+        if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i].1 != '\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        // Block comment
+        if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i].1 == '*' && char_at(i + 1) == Some('/')) {
+                i += 1;
+            }
+            i = (i + 2).min(chars.len());
+            continue;
+        }
+
+        let token = if c == '\'' || c == '"' {
+            // String literal
+            i += 1;
+            while i < chars.len() && chars[i].1 != c {
+                if chars[i].1 == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            Token::Literal
+        } else if c == '`' || (c == '$' && next == Some('`')) {
+            // Quoted identifier, with `` as an escaped backtick
+            let is_param = c == '$';
+            i += if is_param { 2 } else { 1 };
+            let mut name = String::new();
+            while i < chars.len() {
+                if chars[i].1 == '`' {
+                    if char_at(i + 1) == Some('`') {
+                        name.push('`');
+                        i += 2;
+                        continue;
+                    }
+                    break;
+                }
+                name.push(chars[i].1);
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            if is_param {
+                Token::Param(name)
+            } else {
+                Token::Ident(name)
+            }
+        } else if c == '$' && next.is_some_and(is_word_char) {
+            // Parameter
+            i += 1;
+            let mut name = String::new();
+            while i < chars.len() && is_word_char(chars[i].1) {
+                name.push(chars[i].1);
+                i += 1;
+            }
+            Token::Param(name)
+        } else if is_word_char(c) {
+            // Keywords, variables and numbers
+            let mut word = String::new();
+            while i < chars.len() && is_word_char(chars[i].1) {
+                word.push(chars[i].1);
+                i += 1;
+            }
+            Token::Word(word)
+        } else {
+            i += 1;
+            Token::Symbol(c)
+        };
+
+        tokens.push(Spanned {
+            token,
+            start,
+            end: byte_at(i),
+        });
+    }
+
+    tokens
+}
+
+/// Names of all `$param` references in the query text.
+pub fn parameter_names(text: &str) -> BTreeSet<String> {
+    tokenize(text)
+        .into_iter()
+        .filter_map(|spanned| match spanned.token {
+            Token::Param(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Splits a script into statements on `;`, ignoring semicolons in strings and
+/// comments. Statements without any tokens are dropped.
+pub fn split_statements(text: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    for spanned in tokenize(text) {
+        if spanned.token == Token::Symbol(';') {
+            if let Some((start, end)) = current.take() {
+                statements.push(text[start..end].to_string());
+            }
+            continue;
+        }
+        let start = current.map_or(spanned.start, |(start, _)| start);
+        current = Some((start, spanned.end));
+    }
+    if let Some((start, end)) = current {
+        statements.push(text[start..end].to_string());
+    }
+
+    statements
+}
+
+/// Whether the statement creates or drops an index or constraint. The server
+/// does not allow these in the same transaction as data writes.
+pub fn is_schema_statement(text: &str) -> bool {
+    let tokens = tokenize(text);
+    let Some(first) = tokens.first() else {
+        return false;
+    };
+    if !first.is_keyword("CREATE") && !first.is_keyword("DROP") {
+        return false;
+    }
+
+    // The kind comes before the name, so stop at the first bracket
+    tokens
+        .iter()
+        .skip(1)
+        .take_while(|spanned| matches!(spanned.token, Token::Word(_) | Token::Ident(_)))
+        .any(|spanned| spanned.is_keyword("INDEX") || spanned.is_keyword("CONSTRAINT"))
+}
+
+/// Clauses that only read. `CALL` is allowed for subqueries and for
+/// `READ_PROCEDURES`.
+const READ_CLAUSES: [&str; 11] = [
+    "MATCH", "OPTIONAL", "WITH", "UNWIND", "RETURN", "WHERE", "ORDER", "SKIP", "LIMIT", "UNION",
+    "CALL",
+];
+
+/// Keywords that can follow an expression inside a read clause.
+const EXPRESSION_KEYWORDS: [&str; 18] = [
+    "AND",
+    "OR",
+    "XOR",
+    "IN",
+    "IS",
+    "STARTS",
+    "ENDS",
+    "CONTAINS",
+    "AS",
+    "ASC",
+    "ASCENDING",
+    "DESC",
+    "DESCENDING",
+    "YIELD",
+    "WHEN",
+    "THEN",
+    "ELSE",
+    "END",
+];
+
+/// Keywords that come before an expression. All of them are reserved, so
+/// they can't be variable names.
+const PREFIX_KEYWORDS: [&str; 6] = ["NOT", "DISTINCT", "BY", "WITH", "CASE", "WHEN"];
+
+/// Procedures known to only read. Any other procedure call could write.
+const READ_PROCEDURES: [&str; 12] = [
+    "db.labels",
+    "db.relationshipTypes",
+    "db.propertyKeys",
+    "db.schema.visualization",
+    "db.schema.nodeTypeProperties",
+    "db.schema.relTypeProperties",
+    "db.index.fulltext.queryNodes",
+    "db.index.fulltext.queryRelationships",
+    "db.index.vector.queryNodes",
+    "db.info",
+    "db.ping",
+    "dbms.components",
+];
+
+fn is_any(word: &str, keywords: &[&str]) -> bool {
+    keywords
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+/// Looks for anything in the query that is not known to only read, returning
+/// what was found. Words where a clause can start must be one of
+/// `READ_CLAUSES`, and words after an expression must continue it or start a
+/// read clause. This reads the text only, so it errs on the side of rejecting.
+pub fn find_write(text: &str) -> Option<String> {
+    let tokens = tokenize(text);
+    let token_at = |i: usize| tokens.get(i).map(|spanned| &spanned.token);
+
+    // Where a clause must start, e.g. at the start of a statement or subquery
+    let mut clause_start = true;
+    // Where an expression or pattern must start, e.g. after an operator
+    let mut expect_operand = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        let previous = i.checked_sub(1).and_then(token_at);
+        let next = token_at(i + 1);
+        let word = match &tokens[i].token {
+            Token::Word(word) => word,
+            Token::Symbol(c) => {
+                clause_start = match c {
+                    ';' => true,
+                    // Subqueries start with a clause, maps and quantifiers don't
+                    '{' => {
+                        matches!(next, Some(Token::Word(_)))
+                            && !matches!(token_at(i + 2), Some(Token::Symbol(':' | ',' | '}')))
+                    }
+                    _ => false,
+                };
+                expect_operand = match c {
+                    ')' | ']' | '}' => false,
+                    // `RETURN *`, `WITH *` and `YIELD *` end the clause
+                    '*' => !matches!(previous, Some(Token::Word(word))
+                        if is_any(word, &["RETURN", "WITH", "YIELD"])),
+                    _ => true,
+                };
+                i += 1;
+                continue;
+            }
+            _ => {
+                clause_start = false;
+                expect_operand = false;
+                i += 1;
+                continue;
+            }
+        };
+        i += 1;
+
+        if expect_operand && !clause_start {
+            // Variables, functions, literals, property keys and labels are
+            // followed by an operator or the next clause
+            let is_name = matches!(previous, Some(Token::Symbol('.' | ':')))
+                || matches!(previous, Some(Token::Word(word)) if word.eq_ignore_ascii_case("AS"));
+            expect_operand = !is_name && is_any(word, &PREFIX_KEYWORDS);
+            continue;
+        }
+
+        let allowed =
+            is_any(word, &READ_CLAUSES) || (!clause_start && is_any(word, &EXPRESSION_KEYWORDS));
+        if !allowed {
+            return Some(word.clone());
+        }
+
+        clause_start = false;
+        expect_operand = true;
+        if word.eq_ignore_ascii_case("CALL") {
+            // CALL { ... } and CALL (...) { ... } are subqueries, which are
+            // checked like the rest of the text
+            let mut name = String::new();
+            for spanned in &tokens[i..] {
+                match &spanned.token {
+                    Token::Word(part) | Token::Ident(part)
+                        if name.is_empty() || name.ends_with('.') =>
+                    {
+                        name.push_str(part)
+                    }
+                    Token::Symbol('.') => name.push('.'),
+                    _ => break,
+                }
+                i += 1;
+            }
+            if !name.is_empty() {
+                if !is_any(&name, &READ_PROCEDURES) {
+                    return Some(format!("CALL {}", name));
+                }
+                expect_operand = false;
+            }
+        } else if word.eq_ignore_ascii_case("OPTIONAL") {
+            clause_start = true;
+        } else if word.eq_ignore_ascii_case("UNION") {
+            clause_start = true;
+            if matches!(next, Some(Token::Word(word)) if is_any(word, &["ALL", "DISTINCT"])) {
+                i += 1;
+            }
+        } else if is_any(word, &["ASC", "ASCENDING", "DESC", "DESCENDING", "END"]) {
+            expect_operand = false;
+        }
+    }
+
+    None
+}
+
+/// Pairs each token with its bracket nesting depth.
+fn with_depth(tokens: &[Spanned]) -> Vec<(usize, &Spanned)> {
+    let mut depth: usize = 0;
+    tokens
+        .iter()
+        .map(|spanned| {
+            if let Token::Symbol(c) = spanned.token {
+                match c {
+                    '(' | '[' | '{' => {
+                        depth += 1;
+                        return (depth - 1, spanned);
+                    }
+                    ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            (depth, spanned)
+        })
+        .collect()
+}
+
+/// Column names of the final top-level `RETURN` clause, in order. Unaliased
+/// items are named by their expression text, as the server does. `None`
+/// whenever the text alone can't tell: no such clause, `RETURN *`,
+/// `YIELD *`, `UNION` or repeated names.
+pub fn return_columns(text: &str) -> Option<Vec<String>> {
+    let tokens = tokenize(text);
+    let tokens = with_depth(&tokens);
+
+    let top_level: Vec<&Spanned> = tokens
+        .iter()
+        .filter(|(depth, _)| *depth == 0)
+        .map(|(_, spanned)| *spanned)
+        .collect();
+    let yields_all = top_level
+        .windows(2)
+        .any(|pair| pair[0].is_keyword("YIELD") && pair[1].token == Token::Symbol('*'));
+    if yields_all || top_level.iter().any(|spanned| spanned.is_keyword("UNION")) {
+        return None;
+    }
+
+    let return_at = tokens
+        .iter()
+        .rposition(|(depth, spanned)| *depth == 0 && spanned.is_keyword("RETURN"))?;
+
+    let mut items: Vec<Vec<&Spanned>> = vec![Vec::new()];
+    for (depth, spanned) in &tokens[return_at + 1..] {
+        if *depth == 0 {
+            if ["ORDER", "SKIP", "LIMIT", "UNION"]
+                .iter()
+                .any(|keyword| spanned.is_keyword(keyword))
+                || spanned.token == Token::Symbol(';')
+            {
+                break;
+            }
+            if spanned.token == Token::Symbol(',') {
+                items.push(Vec::new());
+                continue;
+            }
+        }
+        items.last_mut()?.push(spanned);
+    }
+
+    if let Some(first) = items.first_mut()
+        && first
+            .first()
+            .is_some_and(|spanned| spanned.is_keyword("DISTINCT"))
+    {
+        first.remove(0);
+    }
+
+    let columns: Vec<String> = items
+        .into_iter()
+        .map(|item| {
+            let (first, last) = (item.first()?, item.last()?);
+            if first.token == Token::Symbol('*') {
+                return None;
+            }
+
+            // `expr AS alias`
+            if item.len() >= 3 && item[item.len() - 2].is_keyword("AS") {
+                return match &last.token {
+                    Token::Word(name) | Token::Ident(name) => Some(name.clone()),
+                    _ => None,
+                };
+            }
+
+            match &first.token {
+                Token::Ident(name) if item.len() == 1 => Some(name.clone()),
+                _ => Some(text[first.start..last.end].to_string()),
+            }
+        })
+        .collect::<Option<_>>()?;
+
+    let unique: BTreeSet<&String> = columns.iter().collect();
+    if unique.len() != columns.len() {
+        return None;
+    }
+
+    Some(columns)
+}
+
+/// Quotes a label, relationship type, property or schema name for use in
+/// query text. Backticks inside the name are doubled.
+pub fn escape_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Checks a name can be used as a label, relationship type or property:
+/// ASCII letters, digits and underscores, not starting with a digit.
+/// Escaping alone is not enough, as some servers decode `\u0060` inside
+/// backticks after the real backticks were doubled.
+pub fn validate_identifier(name: &str) -> anyhow::Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(anyhow!(
+            "Identifier {:?} must only use letters, digits and underscores, and not start with a digit",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Looser check for names the caller explicitly allowed other characters
+/// in. Backslashes are still refused, so no escape sequence can end the
+/// quoted identifier.
+pub fn validate_special_identifier(name: &str) -> anyhow::Result<()> {
+    if name.trim().is_empty() {
+        return Err(anyhow!("Identifiers must not be empty"));
+    }
+    if name.chars().any(|c| c.is_control() || c == '\\') {
+        return Err(anyhow!(
+            "Identifier {:?} must not contain backslashes or control characters",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces `{{name}}` placeholders with already escaped text. Unknown and
+/// unused placeholders are errors, so a typo can't leave a gap in the query.
+pub fn render_template(text: &str, values: &HashMap<String, String>) -> anyhow::Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut used = BTreeSet::new();
+    let mut rest = text;
+    while let Some(open) = rest.find("{{") {
+        let close = rest[open..]
+            .find("}}")
+            .map(|close| open + close)
+            .ok_or_else(|| anyhow!("Unclosed '{{{{' in query template"))?;
+
+        let name = rest[open + 2..close].trim();
+        let value = values
+            .get(name)
+            .ok_or_else(|| anyhow!("No value for placeholder '{{{{{}}}}}'", name))?;
+
+        output.push_str(&rest[..open]);
+        output.push_str(value);
+        used.insert(name);
+        rest = &rest[close + 2..];
+    }
+    output.push_str(rest);
+
+    let mut unused: Vec<&String> = values
+        .keys()
+        .filter(|name| !used.contains(name.as_str()))
+        .collect();
+    if !unused.is_empty() {
+        unused.sort();
+        return Err(anyhow!(
+            "Template values are not used: {}",
+            unused
+                .into_iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<Token> {
+        tokenize(text)
+            .into_iter()
+            .map(|spanned| spanned.token)
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_skips_comments_and_whitespace() {
+        assert_eq!(
+            tokens("MATCH // CREATE\n(n) /* DELETE */ RETURN n"),
+            [
+                Token::Word("MATCH".into()),
+                Token::Symbol('('),
+                Token::Word("n".into()),
+                Token::Symbol(')'),
+                Token::Word("RETURN".into()),
+                Token::Word("n".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_reads_literals_identifiers_and_params() {
+        assert_eq!(
+            tokens(r#"'it\'s' "a;b" `we``ird` $id $`odd name`"#),
+            [
+                Token::Literal,
+                Token::Literal,
+                Token::Ident("we`ird".into()),
+                Token::Param("id".into()),
+                Token::Param("odd name".into()),
+            ]
+        );
+
+        let spanned = tokenize("RETURN  $id");
+        assert_eq!((spanned[1].start, spanned[1].end), (8, 11));
+    }
+
+    #[test]
+    fn tokenize_stops_at_unclosed_text() {
+        assert_eq!(
+            tokens("RETURN 'open"),
+            [Token::Word("RETURN".into()), Token::Literal]
+        );
+        assert_eq!(tokens("RETURN /* open"), [Token::Word("RETURN".into())]);
+    }
+
+    #[test]
+    fn parameter_names_skip_strings_and_comments() {
+        let names =
+            parameter_names("MATCH (n {id: $id}) WHERE n.x = '$not' // $also_not\nRETURN $`a b`");
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), ["a b", "id"]);
+    }
+
+    #[test]
+    fn split_statements_ignores_quoted_semicolons() {
+        assert_eq!(
+            split_statements("CREATE (:A {x: ';'});\n// ;\n;  MATCH (n) RETURN n /* ; */"),
+            ["CREATE (:A {x: ';'})", "MATCH (n) RETURN n"]
+        );
+        assert!(split_statements(" ; // nothing\n").is_empty());
+    }
+
+    #[test]
+    fn schema_statements_are_recognised() {
+        assert!(is_schema_statement(
+            "CREATE CONSTRAINT a IF NOT EXISTS FOR (n:A) REQUIRE n.id IS UNIQUE"
+        ));
+        assert!(is_schema_statement("DROP INDEX a"));
+        assert!(!is_schema_statement("CREATE (n:INDEX)"));
+        assert!(!is_schema_statement("MATCH (n) RETURN n"));
+    }
+
+    #[test]
+    fn return_columns_follow_the_final_return() {
+        assert_eq!(
+            return_columns(
+                "MATCH (n) WITH n RETURN DISTINCT n.name AS name, count(*), `odd col` ORDER BY name"
+            ),
+            Some(vec!["name".into(), "count(*)".into(), "odd col".into()])
+        );
+        assert_eq!(
+            return_columns("MATCH (n) CALL { RETURN 1 AS x } RETURN n"),
+            Some(vec!["n".into()])
+        );
+    }
+
+    #[test]
+    fn return_columns_give_up_when_unsure() {
+        for text in [
+            "CREATE (n)",
+            "MATCH (n) RETURN *",
+            "CALL db.labels() YIELD *",
+            "RETURN 1 AS x UNION RETURN 2 AS x",
+            "RETURN 1 AS x, 2 AS x",
+        ] {
+            assert_eq!(return_columns(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn templates_need_every_value() {
+        let values = HashMap::from([("label".to_string(), "`Player`".to_string())]);
+        assert_eq!(
+            render_template("MATCH (n:{{ label }}) RETURN n", &values).unwrap(),
+            "MATCH (n:`Player`) RETURN n"
+        );
+        assert!(render_template("MATCH (n:{{other}})", &values).is_err());
+        assert!(render_template("MATCH (n)", &values).is_err());
+        assert!(render_template("MATCH (n:{{label)", &values).is_err());
+    }
+
+    #[test]
+    fn plain_identifiers_are_valid() {
+        for name in ["Player", "_id", "steam_id64", "A1"] {
+            assert!(validate_identifier(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn identifiers_reject_special_characters() {
+        for name in [
+            "",
+            " ",
+            "1abc",
+            "a b",
+            "a`b",
+            "a\\u0060b",
+            "a\\`) DETACH DELETE n //",
+            "a-b",
+            "é",
+        ] {
+            assert!(validate_identifier(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn special_identifiers_reject_backslashes() {
+        assert!(validate_special_identifier("My Label").is_ok());
+        assert!(validate_special_identifier("a`b").is_ok());
+        assert!(validate_special_identifier("a\\u0060b").is_err());
+        assert!(validate_special_identifier("a\nb").is_err());
+        assert!(validate_special_identifier("  ").is_err());
+    }
+
+    #[test]
+    fn read_queries_pass() {
+        for text in [
+            "MATCH (n:Player {steam_id: $id}) RETURN n",
+            "MATCH (n) WHERE n.x IN [1, 2] AND NOT n:Banned RETURN n ORDER BY n.x DESC SKIP 1 LIMIT 5",
+            "OPTIONAL MATCH (n)-[r:KNOWS*1..3]->(m) RETURN DISTINCT m.name AS name",
+            "UNWIND $rows AS row MATCH (n {id: row.id}) WITH n, row RETURN n {.name, .age}",
+            "MATCH (a) RETURN a UNION ALL MATCH (b) RETURN b",
+            "CALL db.labels() YIELD label RETURN label",
+            "CALL db.labels YIELD * RETURN *",
+            "MATCH (n) CALL (n) { MATCH (n)-->(m) RETURN count(*) AS c } RETURN n, c",
+            "RETURN CASE WHEN $x IS NOT NULL THEN 1 ELSE 2 END AS v",
+            "MATCH (n) WHERE n.name STARTS WITH 'a' OR n.name ENDS WITH 'b' RETURN n",
+            "MATCH (n) WHERE EXISTS { MATCH (n)-->() } RETURN count { MATCH (n)-->() } AS c",
+            "RETURN [x IN range(1, 3) WHERE x > 1 | x * 2] AS xs",
+            "MATCH p = shortestPath((a)-[*]-(b)) RETURN p",
+            "MATCH ((a)-->(b)){1,3} RETURN a",
+            "MATCH (n) RETURN n.with, n.create // CREATE (m)",
+        ] {
+            assert_eq!(find_write(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn write_queries_are_found() {
+        for (text, found) in [
+            ("CREATE (n)", "CREATE"),
+            ("MATCH (n) SET n.x = 1", "SET"),
+            ("MATCH (n) DETACH DELETE n", "DETACH"),
+            ("MATCH (n) WITH n LIMIT 1 MERGE (m)", "MERGE"),
+            ("MATCH (n) WHERE n.x IS NULL REMOVE n.x", "REMOVE"),
+            ("MATCH (n) RETURN * CREATE (m)", "CREATE"),
+            ("MATCH (n) RETURN n.with CREATE (m)", "CREATE"),
+            ("MATCH (n) WITH n AS not CREATE (m)", "CREATE"),
+            ("MATCH (n) CALL { CREATE (m) } RETURN n", "CREATE"),
+            ("MATCH (n) FOREACH (x IN [1] | CREATE (m))", "FOREACH"),
+            ("LOAD CSV FROM 'file:///a' AS row RETURN row", "LOAD"),
+            ("START DATABASE neo4j", "START"),
+            ("STOP DATABASE neo4j", "STOP"),
+            ("TERMINATE TRANSACTIONS 'tx-1'", "TERMINATE"),
+            ("RENAME ROLE a TO b", "RENAME"),
+            ("ENABLE SERVER 'id'", "ENABLE"),
+            ("DEALLOCATE DATABASES FROM SERVER 'id'", "DEALLOCATE"),
+            ("REALLOCATE DATABASES", "REALLOCATE"),
+            ("USE system SHOW USERS", "USE"),
+            ("PROFILE MATCH (n) DELETE n", "PROFILE"),
+            ("MATCH (n) RETURN n; DROP INDEX a", "DROP"),
+            ("OPTIONAL CREATE (n)", "CREATE"),
+        ] {
+            assert_eq!(find_write(text).as_deref(), Some(found), "{}", text);
+        }
+    }
+
+    #[test]
+    fn only_read_procedures_pass() {
+        assert_eq!(find_write("CALL dbms.components()"), None);
+        assert_eq!(
+            find_write("CALL apoc.create.node(['A'], {}) YIELD node RETURN node").as_deref(),
+            Some("CALL apoc.create.node")
+        );
+        assert_eq!(
+            find_write("MATCH (n) CALL { CALL dbms.setConfigValue('a', 'b') }").as_deref(),
+            Some("CALL dbms.setConfigValue")
+        );
+    }
+
+    #[test]
+    fn escaping_doubles_backticks() {
+        assert_eq!(escape_identifier("Player"), "`Player`");
+        assert_eq!(escape_identifier("a`b"), "`a``b`");
+    }
+}
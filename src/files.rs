@@ -0,0 +1,96 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::anyhow;
+
+/// Game directories searched for Cypher files, relative to the server root.
+const SEARCH_ROOTS: [&str; 2] = ["garrysmod/data", "garrysmod/lua"];
+
+pub struct CypherFile {
+    pub name: String,
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// Reads every `.cypher` file in `dir` from the server's data and lua
+/// directories, sorted by file name.
+pub fn read_cypher_files(dir: &str) -> anyhow::Result<Vec<CypherFile>> {
+    let relative = Path::new(dir);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow!(
+            "Invalid directory '{}': must be relative and must not leave the game folder",
+            dir
+        ));
+    }
+
+    let mut files = Vec::new();
+    let mut found_dir = false;
+    for root in SEARCH_ROOTS {
+        let path = Path::new(root).join(relative);
+        if !path.is_dir() {
+            continue;
+        }
+        found_dir = true;
+
+        for entry in std::fs::read_dir(&path)? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension().is_none_or(|ext| ext != "cypher") {
+                continue;
+            }
+
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Could not read {}: {}", path.display(), e))?;
+
+            files.push(CypherFile {
+                name,
+                path,
+                contents,
+            });
+        }
+    }
+
+    if !found_dir {
+        return Err(anyhow!(
+            "Directory '{}' was not found in {}",
+            dir,
+            SEARCH_ROOTS.join(" or ")
+        ));
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/// FNV-1a hash of the file contents as hex, with line endings normalised so
+/// a checkout with CRLF endings gives the same checksum.
+pub fn checksum(contents: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in contents.replace("\r\n", "\n").bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_is_stable_hex() {
+        assert_eq!(checksum(""), "cbf29ce484222325");
+        assert_eq!(checksum("CREATE (n)").len(), 16);
+        assert_ne!(checksum("CREATE (n)"), checksum("CREATE (m)"));
+    }
+
+    #[test]
+    fn checksum_ignores_line_endings() {
+        assert_eq!(checksum("a\r\nb\r\n"), checksum("a\nb\n"));
+    }
+}
@@ -10,6 +10,8 @@ use tokio::runtime::Runtime;
 use std::ffi::CStr;
 
 mod api;
+mod cypher;
+mod files;
 mod mapping;
 mod runtime;
 
@@ -36,7 +38,21 @@ unsafe fn gmod13_open(l: lua::State) -> i32 {
 
     let regs = lua_regs! [
         "Query" => api::query::new_query,
-        "Graph" => api::graph::new_graph
+        "Graph" => api::graph::new_graph,
+        "Register" => api::registry::register,
+        "Get" => api::registry::get,
+        "List" => api::registry::list,
+        "LoadQueries" => api::named::load_queries,
+        "Named" => api::named::named_query,
+        "AllowQuery" => api::allowlist::allow_query,
+        "Migrate" => api::migrate::migrate,
+        "Model" => api::model::new_model,
+        "Ident" => api::ident::ident,
+        "Label" => api::ident::label,
+        "Cypher" => api::builder::new_cypher,
+        "Await" => api::promise::await_promise,
+        "All" => api::promise::all,
+        "Race" => api::promise::race
     ];
 
     l.register(NAMESPACE.as_ptr(), regs.as_ptr());
@@ -58,6 +74,7 @@ fn gmod13_close(l: lua::State) -> i32 {
     );
     println!("{}", log_message);
 
+    api::registry::clear();
     runtime::unload(l);
 
     0
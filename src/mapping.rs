@@ -1,13 +1,62 @@
-use anyhow::{Error, anyhow};
+use anyhow::anyhow;
 use gmod::{
-    LUA_TBOOLEAN, LUA_TNUMBER, LUA_TSTRING, LUA_TTABLE,
+    LUA_TBOOLEAN, LUA_TFUNCTION, LUA_TLIGHTUSERDATA, LUA_TNIL, LUA_TNONE, LUA_TNUMBER, LUA_TSTRING,
+    LUA_TTABLE, LUA_TTHREAD, LUA_TUSERDATA,
     lua::{self},
     push_to_lua::PushToLua,
 };
 
 use neo4rs::{BoltBoolean, BoltFloat, BoltInteger, BoltList, BoltMap, BoltString, BoltType};
 
-unsafe fn get_table_key(l: &lua::State, key_type: i32) -> anyhow::Result<String> {
+pub fn lua_type_name(lua_type: i32) -> &'static str {
+    match lua_type {
+        LUA_TNONE => "no value",
+        LUA_TNIL => "nil",
+        LUA_TBOOLEAN => "boolean",
+        LUA_TLIGHTUSERDATA => "lightuserdata",
+        LUA_TNUMBER => "number",
+        LUA_TSTRING => "string",
+        LUA_TTABLE => "table",
+        LUA_TFUNCTION => "function",
+        LUA_TUSERDATA => "userdata",
+        LUA_TTHREAD => "thread",
+        _ => "unknown",
+    }
+}
+
+fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Extends a key path the way the value would be indexed in Lua,
+/// e.g. `items[3].owner`.
+fn child_path(path: &str, key: &str, numeric: bool) -> String {
+    if numeric {
+        format!("{}[{}]", path, key)
+    } else if is_identifier(key) {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    } else {
+        format!("{}[{:?}]", path, key)
+    }
+}
+
+fn describe_path(path: &str) -> String {
+    if path.is_empty() {
+        "the top level".to_string()
+    } else {
+        format!("'{}'", path)
+    }
+}
+
+unsafe fn get_table_key(l: &lua::State, key_type: i32, path: &str) -> anyhow::Result<String> {
     if key_type == LUA_TSTRING {
         let key = l.check_string(-2)?;
         return Ok(key);
@@ -18,10 +67,11 @@ unsafe fn get_table_key(l: &lua::State, key_type: i32) -> anyhow::Result<String>
         return Ok(key.to_string());
     }
 
-    Err(Error::msg(format!(
-        "Could not retrieve table key due to unsupported key type: {}",
-        key_type
-    )))
+    Err(anyhow!(
+        "Unsupported table key of type {} at {}; keys must be strings or numbers",
+        lua_type_name(key_type),
+        describe_path(path)
+    ))
 }
 
 fn is_sequential_table(l: &lua::State, index: i32) -> bool {
@@ -48,9 +98,55 @@ fn is_sequential_table(l: &lua::State, index: i32) -> bool {
     count == len as i64
 }
 
-pub fn lua_table_to_boltmap(l: lua::State, index: i32) -> anyhow::Result<BoltMap> {
+/// Converts the Lua value at `index`. `path` names the value in error
+/// messages, so callers converting a named parameter should pass its name.
+pub fn lua_value_to_bolttype(l: lua::State, index: i32, path: &str) -> anyhow::Result<BoltType> {
+    // Nested conversions push onto the stack, so relative indices would drift
+    let index = if index < 0 {
+        l.get_top() + index + 1
+    } else {
+        index
+    };
+
+    match l.lua_type(index) {
+        LUA_TSTRING => {
+            let value = l.check_string(index)?;
+            Ok(BoltType::String(BoltString::from(value)))
+        }
+        LUA_TNUMBER => {
+            let value = l.to_number(index);
+            if value.fract() == 0.0 {
+                Ok(BoltType::Integer(BoltInteger::from(value as i64)))
+            } else {
+                Ok(BoltType::Float(BoltFloat::new(value)))
+            }
+        }
+        LUA_TTABLE => {
+            if is_sequential_table(&l, index) {
+                Ok(BoltType::List(lua_table_to_boltlist(l, index, path)?))
+            } else {
+                Ok(BoltType::Map(lua_table_to_boltmap(l, index, path)?))
+            }
+        }
+        LUA_TBOOLEAN => {
+            let bool = l.check_boolean(index)?;
+            Ok(BoltType::Boolean(BoltBoolean::new(bool)))
+        }
+        value_type => Err(anyhow!(
+            "Cannot convert value of type {} at {}",
+            lua_type_name(value_type),
+            describe_path(path)
+        )),
+    }
+}
+
+pub fn lua_table_to_boltmap(l: lua::State, index: i32, path: &str) -> anyhow::Result<BoltMap> {
     if !l.is_table(index) {
-        return Err(anyhow!("Expected a table"));
+        return Err(anyhow!(
+            "Expected a table at {}, got {}",
+            describe_path(path),
+            lua_type_name(l.lua_type(index))
+        ));
     }
 
     let mut map = BoltMap::new();
@@ -59,39 +155,20 @@ pub fn lua_table_to_boltmap(l: lua::State, index: i32) -> anyhow::Result<BoltMap
         while l.next(index) != 0 {
             let key_type = l.lua_type(-2);
 
-            let key = get_table_key(&l, key_type)?;
-            let key = BoltString::from(key);
-
-            let value_type = l.lua_type(-1);
-            match value_type {
-                LUA_TSTRING => {
-                    let value = l.check_string(-1)?;
-                    map.put(key, BoltType::String(BoltString::from(value)));
-                }
-                LUA_TNUMBER => {
-                    let value = l.to_number(-1);
-                    if value.fract() == 0.0 {
-                        map.put(key, BoltType::Integer(BoltInteger::from(value as i64)));
-                    } else {
-                        map.put(key, BoltType::Float(BoltFloat::new(value)));
-                    }
-                }
-                LUA_TTABLE => {
-                    if is_sequential_table(&l, l.get_top()) {
-                        let nested = lua_table_to_boltlist(l, l.get_top())?;
-                        map.put(key, BoltType::List(nested));
-                    } else {
-                        let nested_table = lua_table_to_boltmap(l, l.get_top())?;
-                        map.put(key, BoltType::Map(nested_table));
-                    }
+            let key = match get_table_key(&l, key_type, path) {
+                Ok(key) => key,
+                Err(err) => {
+                    l.pop_n(2);
+                    return Err(err);
                 }
-                LUA_TBOOLEAN => {
-                    let bool = l.check_boolean(-1)?;
-                    map.put(key, BoltType::Boolean(BoltBoolean::new(bool)));
-                }
-                _ => {
-                    l.pop_n(1);
-                    return Err(Error::msg(format!("Unsupported table value type")));
+            };
+            let value_path = child_path(path, &key, key_type == LUA_TNUMBER);
+
+            match lua_value_to_bolttype(l, -1, &value_path) {
+                Ok(value) => map.put(BoltString::from(key), value),
+                Err(err) => {
+                    l.pop_n(2);
+                    return Err(err);
                 }
             }
             l.pop_n(1);
@@ -100,36 +177,17 @@ pub fn lua_table_to_boltmap(l: lua::State, index: i32) -> anyhow::Result<BoltMap
     Ok(map)
 }
 
-pub fn lua_table_to_boltlist(l: lua::State, index: i32) -> anyhow::Result<BoltList> {
+pub fn lua_table_to_boltlist(l: lua::State, index: i32, path: &str) -> anyhow::Result<BoltList> {
     let mut list = BoltList::new();
     for i in 1..=l.len(index) as i32 {
         l.raw_geti(index, i);
-        let value_type = l.lua_type(-1);
 
-        let bolt_value = match value_type {
-            LUA_TSTRING => {
-                let value = l.check_string(-1)?;
-                BoltType::String(BoltString::from(value))
-            }
-            LUA_TNUMBER => {
-                let value = l.to_number(-1);
-                if value.fract() == 0.0 {
-                    BoltType::Integer(BoltInteger::from(value as i64))
-                } else {
-                    BoltType::Float(BoltFloat::new(value))
-                }
-            }
-            LUA_TTABLE => {
-                l.pop();
-                return Err(Error::msg("Nested tables in lists are not supported"));
-            }
-            LUA_TBOOLEAN => {
-                let bool = l.check_boolean(-1)?;
-                BoltType::Boolean(BoltBoolean::new(bool))
-            }
-            _ => {
+        let value_path = child_path(path, &i.to_string(), true);
+        let bolt_value = match lua_value_to_bolttype(l, -1, &value_path) {
+            Ok(value) => value,
+            Err(err) => {
                 l.pop();
-                return Err(Error::msg("Unsupported table value type"));
+                return Err(err);
             }
         };
 
@@ -190,3 +248,38 @@ pub fn map_type_to_lua(l: lua::State, item: &BoltType) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_read_like_lua_indexing() {
+        assert_eq!(child_path("", "items", false), "items");
+        assert_eq!(child_path("items", "3", true), "items[3]");
+        assert_eq!(child_path("items[3]", "owner", false), "items[3].owner");
+        assert_eq!(child_path("", "1", true), "[1]");
+    }
+
+    #[test]
+    fn odd_keys_are_quoted() {
+        assert_eq!(child_path("meta", "steam id", false), r#"meta["steam id"]"#);
+        assert_eq!(child_path("meta", "1st", false), r#"meta["1st"]"#);
+        assert_eq!(child_path("", "a\"b", false), r#"["a\"b"]"#);
+    }
+
+    #[test]
+    fn identifiers_follow_lua_rules() {
+        assert!(is_identifier("_private"));
+        assert!(is_identifier("name2"));
+        assert!(!is_identifier(""));
+        assert!(!is_identifier("2name"));
+        assert!(!is_identifier("a-b"));
+    }
+
+    #[test]
+    fn paths_are_described_for_errors() {
+        assert_eq!(describe_path(""), "the top level");
+        assert_eq!(describe_path("items[3]"), "'items[3]'");
+    }
+}
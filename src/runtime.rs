@@ -6,17 +6,23 @@ use gmod::lua;
 use tokio::runtime::{Builder, Runtime};
 use tokio_util::task::TaskTracker;
 
+use crate::api::query::ParamValidation;
+
 pub const DEFAULT_WORKER_THREADS: u16 = 1;
 pub const DEFAULT_CONNECTION_TIMEOUT: u32 = 20;
 
 static mut RUN_TIME: MaybeUninit<Runtime> = MaybeUninit::uninit();
 static mut TASK_TRACKER: MaybeUninit<TaskTracker> = MaybeUninit::uninit();
 static mut SHUTDOWN_TIMEOUT: u32 = DEFAULT_CONNECTION_TIMEOUT;
+static mut PARAM_VALIDATION: ParamValidation = ParamValidation::Warning;
+static mut STRICT_QUERIES: bool = false;
 
 pub(super) fn load(l: lua::State) {
     let worker_threads = get_max_worker_threads(l);
     unsafe {
         SHUTDOWN_TIMEOUT = get_graceful_shutdown_timeout(l);
+        PARAM_VALIDATION = get_param_validation(l);
+        STRICT_QUERIES = get_strict_queries(l);
     }
 
     let run_time = Builder::new_multi_thread()
@@ -67,6 +73,37 @@ fn read_tracker<'a>() -> &'a TaskTracker {
     unsafe { TASK_TRACKER.assume_init_ref() }
 }
 
+/// Blocks the calling (game) thread until the future completes on the module runtime.
+pub fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+    read().block_on(fut)
+}
+
+/// Whether the server has started running ticks, i.e. initialization is over.
+pub fn is_server_ticking(l: lua::State) -> bool {
+    let mut ticking = false;
+
+    l.get_global(c"engine");
+    if l.is_table(-1) {
+        l.get_field(-1, c"TickCount");
+        let success = l.pcall_ignore(|| 1);
+        if success {
+            ticking = l.to_number(-1) > 0.0;
+            l.pop();
+        }
+    }
+    l.pop();
+
+    ticking
+}
+
+pub fn param_validation() -> ParamValidation {
+    unsafe { PARAM_VALIDATION }
+}
+
+pub fn strict_queries() -> bool {
+    unsafe { STRICT_QUERIES }
+}
+
 pub fn run_async<F>(fut: F) -> tokio::task::JoinHandle<F::Output>
 where
     F: std::future::Future + Send + 'static,
@@ -75,6 +112,15 @@ where
     read().spawn(read_tracker().track_future(fut))
 }
 
+/// Spawns long-running work that is not waited for when the module unloads.
+pub fn run_background<F>(fut: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    read().spawn(fut)
+}
+
 fn get_max_worker_threads(l: lua::State) -> u16 {
     let mut max_worker_threads = DEFAULT_WORKER_THREADS;
 
@@ -144,3 +190,80 @@ fn get_graceful_shutdown_timeout(l: lua::State) -> u32 {
 
     timeout
 }
+
+fn get_param_validation(l: lua::State) -> ParamValidation {
+    let mut mode = ParamValidation::Warning;
+
+    l.get_global(c"CreateConVar");
+    let success = l.pcall_ignore(|| {
+        l.push_string("NEO4J_PARAM_VALIDATION");
+        l.push_string("warning");
+        l.create_table(2, 0);
+        {
+            l.get_global(c"FCVAR_ARCHIVE");
+            l.raw_seti(-2, 1);
+
+            l.get_global(c"FCVAR_PROTECTED");
+            l.raw_seti(-2, 2);
+        }
+        l.push_string("How to treat missing or unused query parameters: error, warning or off");
+        1
+    });
+
+    if success {
+        l.get_field(-1, c"GetString");
+        let success = l.pcall_ignore(|| {
+            l.push_value(-2);
+            1
+        });
+        if success {
+            if l.is_string(-1) {
+                match ParamValidation::parse(&l.get_string_unchecked(-1)) {
+                    Some(parsed) => mode = parsed,
+                    None => eprintln!(
+                        "NEO4J_PARAM_VALIDATION must be one of error, warning or off; using warning"
+                    ),
+                }
+            }
+            l.pop();
+        }
+        l.pop();
+    }
+
+    mode
+}
+
+fn get_strict_queries(l: lua::State) -> bool {
+    let mut strict = false;
+
+    l.get_global(c"CreateConVar");
+    let success = l.pcall_ignore(|| {
+        l.push_string("NEO4J_STRICT_QUERIES");
+        l.push_number(0);
+        l.create_table(2, 0);
+        {
+            l.get_global(c"FCVAR_ARCHIVE");
+            l.raw_seti(-2, 1);
+
+            l.get_global(c"FCVAR_PROTECTED");
+            l.raw_seti(-2, 2);
+        }
+        l.push_string("Only allow query text registered with neo4j.AllowQuery (1) or any text (0)");
+        1
+    });
+
+    if success {
+        l.get_field(-1, c"GetInt");
+        let success = l.pcall_ignore(|| {
+            l.push_value(-2);
+            1
+        });
+        if success {
+            strict = l.to_number(-1) != 0.0;
+            l.pop();
+        }
+        l.pop();
+    }
+
+    strict
+}